@@ -1,20 +1,40 @@
 use core::convert::TryFrom;
+use std::rc::Rc;
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+mod batching;
+pub use batching::*;
+
+mod cache;
+pub use cache::*;
+
+mod failure_policy;
+pub use failure_policy::*;
+
 mod operation;
 pub use operation::*;
 
+mod response;
+pub use response::*;
+
+mod retry_policy;
+pub use retry_policy::*;
+
 mod source;
 pub use source::*;
 
+pub mod validation;
+
 pub mod api;
 
 #[derive(Debug, Error)]
 pub enum MissingError {
     #[error("no backend configured")]
     Backend,
+    #[error("backend {0} not configured")]
+    NamedBackend(String),
     #[error("no services configured")]
     Services,
 }
@@ -28,6 +48,20 @@ pub enum ApplicationKind {
     Oidc,
 }
 
+// What to do with requests when 3scale can't be reached
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnBackendError {
+    Deny,
+    Allow,
+}
+
+impl Default for OnBackendError {
+    fn default() -> Self {
+        Self::Deny
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "api")]
 pub enum Configuration {
@@ -47,6 +81,37 @@ impl Configuration {
             Self::V1(c) => c,
         }
     }
+
+    // Configurations are shared with contexts as snapshots. Changes are applied
+    // on a copy that then replaces the shared one as a whole, so contexts holding
+    // the previous snapshot never see a partially updated configuration.
+    pub fn update<F, R>(this: &mut Rc<Self>, f: F) -> R
+    where
+        F: FnOnce(&mut api::v1::Configuration) -> R,
+    {
+        let mut config = this.as_ref().clone();
+        let ret = f(config.get_mut());
+        *this = Rc::new(config);
+        ret
+    }
+
+    // Replaces the service with the same id, ie. with its fetched config,
+    // returning false and leaving the snapshot as is if there is none.
+    pub fn replace_service(this: &mut Rc<Self>, service: crate::threescale::Service) -> bool {
+        if this.get().get_service(service.id()).is_none() {
+            return false;
+        }
+        Self::update(this, |config| {
+            let current = config
+                .services
+                .as_mut()
+                .and_then(|services| services.iter_mut().find(|sv| sv.id() == service.id()));
+            if let Some(current) = current {
+                *current = service;
+            }
+        });
+        true
+    }
 }
 
 // Default to JSON configuration deserialization
@@ -130,6 +195,7 @@ mod test {
                 },
                 extensions: Some(vec!["no_body".to_string()]),
             }),
+            shadow_backend: None,
             services: Some(vec![Service {
                 id: "2555417834780".into(),
                 token: Some("service_token".into()),
@@ -259,14 +325,75 @@ mod test {
                     usages: vec![Usage {
                         name: "Hits".into(),
                         delta: 1,
+                        plans: None,
                     }],
                     last: Default::default(),
+                    response_bytes_metric: None,
                 }],
+                passthrough_params: None,
+                method_metric: None,
+                backend_paths: None,
+                signature: None,
+                strip_matrix_params: None,
+                unmetered_apps: None,
+                auth_source_header: None,
+                plan: None,
+                usage_merge: None,
+                plan_header: None,
+                report_on_response: None,
+                failure_policy: None,
+                grpc: None,
+                upstream: None,
+                retry_policy: None,
+                no_match_behavior: None,
+                response_rules: None,
+                backend: None,
+                bypass: None,
+                sanitize: None,
             }]),
+            backends: None,
             passthrough_metadata: Some(true),
+            metadata_namespace: None,
+            log_level: None,
+            decision_log: None,
+            cache: None,
+            deny_response: None,
+            rejection_reason_header: None,
+            environment: None,
+            on_backend_error: None,
+            batching: None,
+            coalesce_calls: None,
         })
     }
 
+    #[test]
+    fn fetched_services_only_reach_new_snapshots() {
+        let mut current = Rc::new(get_config());
+        // held by a context created before fetching the service
+        let snapshot = Rc::clone(&current);
+        let mut fetched = snapshot.get().services().unwrap()[0].clone();
+        fetched.token = Some("fetched-token".into());
+        fetched.mapping_rules.push(fetched.mapping_rules[0].clone());
+        let rules = fetched.mapping_rules.len();
+
+        assert!(Configuration::replace_service(&mut current, fetched));
+
+        // neither the token nor the rules of the fetched service
+        let service = &snapshot.get().services().unwrap()[0];
+        assert_eq!(service.token(), Some("service_token"));
+        assert_eq!(service.mapping_rules().len(), rules - 1);
+        // both of them
+        let service = &current.get().services().unwrap()[0];
+        assert_eq!(service.token(), Some("fetched-token"));
+        assert_eq!(service.mapping_rules().len(), rules);
+
+        let mut unknown = service.clone();
+        unknown.id = "unknown-service".into();
+        let previous = Rc::clone(&current);
+        assert!(!Configuration::replace_service(&mut current, unknown));
+        assert!(Rc::ptr_eq(&previous, &current));
+    }
+
     #[cfg(any(feature = "json_config", not(feature = "yaml_config")))]
     mod json {
         use super::*;
@@ -1,16 +1,38 @@
 use serde::{Deserialize, Serialize};
 
-use crate::configuration::MissingError;
-use crate::threescale::{Backend, Service, System};
+use crate::configuration::{Batching, Cache, DenyResponse, MissingError, OnBackendError};
+use crate::log::LogLevel;
+use crate::threescale::{Backend, Environment, Service, System};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename = "3scale")]
 pub struct Configuration {
     pub system: Option<System>,
     pub backend: Option<Backend>,
+    // other backends, used by the services referencing them by name
+    pub backends: Option<Vec<Backend>>,
+    // receives a copy of the calls to the backend, its verdicts are only compared
+    pub shadow_backend: Option<Backend>,
     pub services: Option<Vec<Service>>,
     // pass request to the next filter in the chain
     pub passthrough_metadata: Option<bool>,
+    // pass the information as properties under this namespace rather than as headers
+    pub metadata_namespace: Option<String>,
+    // minimum level of the messages to be logged
+    pub log_level: Option<LogLevel>,
+    // log a JSON record of how each request was handled
+    pub decision_log: Option<bool>,
+    pub cache: Option<Cache>,
+    pub deny_response: Option<DenyResponse>,
+    // tell clients why 3scale rejected their requests in this response header
+    pub rejection_reason_header: Option<String>,
+    // only services in this environment are used
+    pub environment: Option<Environment>,
+    pub on_backend_error: Option<OnBackendError>,
+    // usage is queued and reported periodically rather than with each authorization
+    pub batching: Option<Batching>,
+    // requests waiting on an identical authorization in flight share its verdict
+    pub coalesce_calls: Option<bool>,
 }
 
 impl Configuration {
@@ -23,15 +45,81 @@ impl Configuration {
         self.backend.as_ref()
     }
 
+    pub const fn backends(&self) -> Option<&Vec<Backend>> {
+        self.backends.as_ref()
+    }
+
+    pub const fn shadow_backend(&self) -> Option<&Backend> {
+        self.shadow_backend.as_ref()
+    }
+
     pub const fn services(&self) -> Option<&Vec<Service>> {
         self.services.as_ref()
     }
 
+    pub fn metadata_namespace(&self) -> Option<&str> {
+        self.metadata_namespace.as_deref()
+    }
+
+    pub const fn log_level(&self) -> Option<LogLevel> {
+        self.log_level
+    }
+
+    pub fn decision_log(&self) -> bool {
+        self.decision_log.unwrap_or(false)
+    }
+
+    pub const fn cache(&self) -> Option<&Cache> {
+        self.cache.as_ref()
+    }
+
+    pub const fn deny_response(&self) -> Option<&DenyResponse> {
+        self.deny_response.as_ref()
+    }
+
+    pub fn rejection_reason_header(&self) -> Option<&str> {
+        self.rejection_reason_header.as_deref()
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.environment.unwrap_or_default()
+    }
+
+    pub fn on_backend_error(&self) -> OnBackendError {
+        self.on_backend_error.unwrap_or_default()
+    }
+
+    pub const fn batching(&self) -> Option<&Batching> {
+        self.batching.as_ref()
+    }
+
+    pub fn coalesce_calls(&self) -> bool {
+        self.coalesce_calls.unwrap_or(false)
+    }
+
     pub fn get_backend(&self) -> Result<&Backend, MissingError> {
         self.backend().ok_or(MissingError::Backend)
     }
 
+    // The backend referenced by the service, or the default one.
+    pub fn get_service_backend(&self, service: &Service) -> Result<&Backend, MissingError> {
+        let name = match service.backend() {
+            Some(name) => name,
+            None => return self.get_backend(),
+        };
+        self.backend()
+            .into_iter()
+            .chain(self.backends().into_iter().flatten())
+            .find(|backend| backend.name() == Some(name))
+            .ok_or_else(|| MissingError::NamedBackend(name.to_string()))
+    }
+
     pub fn get_services(&self) -> Result<&Vec<Service>, MissingError> {
         self.services().ok_or(MissingError::Services)
     }
+
+    pub fn get_service(&self, id: &str) -> Option<&Service> {
+        self.services()
+            .and_then(|services| services.iter().find(|svc| svc.id() == id))
+    }
 }
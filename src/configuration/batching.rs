@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_FLUSH_INTERVAL_SECS: u64 = 10;
+const DEFAULT_MAX_PENDING: usize = 100;
+
+// Reporting of usage in batches rather than with each authorization.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Batching {
+    // seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flush_interval: Option<u64>,
+    // requests queued before flushing without waiting for the interval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pending: Option<usize>,
+}
+
+impl Batching {
+    pub fn flush_interval(&self) -> u64 {
+        self.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL_SECS)
+    }
+
+    pub fn max_pending(&self) -> usize {
+        self.max_pending.unwrap_or(DEFAULT_MAX_PENDING)
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_TTL_SECS: u64 = 60;
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+// Cache of authorization verdicts shared by all worker VMs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Cache {
+    // seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_entries: Option<usize>,
+    // deny cache hits of applications out of their limits without asking 3scale
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_limits: Option<bool>,
+    // changing it purges all the entries cached so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+}
+
+impl Cache {
+    pub fn ttl(&self) -> u64 {
+        self.ttl.unwrap_or(DEFAULT_TTL_SECS)
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES)
+    }
+
+    pub fn local_limits(&self) -> bool {
+        self.local_limits.unwrap_or(false)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.unwrap_or(0)
+    }
+}
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use super::{OnBackendError, Response};
+
+const LIMITS_EXCEEDED_STATUS: u32 = 429;
+
+// Per service handling of requests that could not be authorized, overriding
+// the top level settings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FailurePolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_backend_error: Option<OnBackendError>,
+    // sent when no credentials were found in the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials_missing: Option<Response>,
+    // sent when the application exceeded its usage limits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits_exceeded: Option<Response>,
+    // sent when 3scale could not be reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend_error: Option<Response>,
+}
+
+impl FailurePolicy {
+    pub const fn on_backend_error(&self) -> Option<OnBackendError> {
+        self.on_backend_error
+    }
+
+    pub const fn credentials_missing(&self) -> Option<&Response> {
+        self.credentials_missing.as_ref()
+    }
+
+    // the status defaults to the one for exceeded limits rather than for denials
+    pub fn limits_exceeded(&self) -> Option<(&Response, u32)> {
+        self.limits_exceeded
+            .as_ref()
+            .map(|response| (response, response.status.unwrap_or(LIMITS_EXCEEDED_STATUS)))
+    }
+
+    pub const fn backend_error(&self) -> Option<&Response> {
+        self.backend_error.as_ref()
+    }
+}
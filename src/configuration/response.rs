@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_STATUS: u32 = 403;
+const DEFAULT_BODY: &str = "Access forbidden.\n";
+
+// A response sent locally to the client.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+impl Response {
+    pub fn status(&self) -> u32 {
+        self.status.unwrap_or(DEFAULT_STATUS)
+    }
+
+    pub fn headers(&self) -> Vec<(&str, &str)> {
+        self.headers
+            .iter()
+            .flatten()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    }
+
+    pub fn body(&self) -> &str {
+        self.body.as_deref().unwrap_or(DEFAULT_BODY)
+    }
+}
+
+// Responses sent to clients when requests are not authorized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DenyResponse {
+    #[serde(flatten)]
+    pub response: Response,
+    // used instead when 3scale could not be reached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend_unreachable: Option<Response>,
+}
+
+impl DenyResponse {
+    pub const fn response(&self) -> &Response {
+        &self.response
+    }
+
+    pub fn backend_unreachable(&self) -> &Response {
+        self.backend_unreachable.as_ref().unwrap_or(&self.response)
+    }
+}
@@ -0,0 +1,65 @@
+use core::convert::TryFrom;
+use core::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BACKOFF: u32 = 2;
+
+// Retries of authorizations failing with a 5xx status or timing out.
+//
+// Filters cannot wait before dispatching a call, so retries are sent right
+// after a failure, with no delay, and only lengthen the timeout of each attempt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    // including the first attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    // factor the timeout is multiplied by on each retry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<u32>,
+}
+
+impl RetryPolicy {
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS)
+    }
+
+    pub fn backoff(&self) -> u32 {
+        self.backoff.unwrap_or(DEFAULT_BACKOFF)
+    }
+
+    // timeout in ms of the given attempt, counting from zero
+    pub fn timeout_ms(&self, timeout: Duration, attempt: u32) -> u64 {
+        let factor = u64::from(self.backoff()).saturating_pow(attempt);
+        let millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+        millis.saturating_mul(factor)
+    }
+
+    // timeout in ms of all the attempts together
+    pub fn total_timeout_ms(&self, timeout: Duration) -> u64 {
+        (0..self.max_attempts()).fold(0, |total: u64, attempt| {
+            total.saturating_add(self.timeout_ms(timeout, attempt))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backs_off_timeouts() {
+        let policy = RetryPolicy {
+            max_attempts: None,
+            backoff: None,
+        };
+        let timeout = Duration::from_millis(500);
+
+        assert_eq!(policy.max_attempts(), 3);
+        assert_eq!(policy.timeout_ms(timeout, 0), 500);
+        assert_eq!(policy.timeout_ms(timeout, 2), 2000);
+        assert_eq!(policy.timeout_ms(timeout, 64), u64::MAX);
+        assert_eq!(policy.total_timeout_ms(timeout), 500 + 1000 + 2000);
+    }
+}
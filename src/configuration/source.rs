@@ -1,6 +1,7 @@
 use std::borrow::Cow;
 
-use log::debug;
+use log::{debug, error};
+use prost_types::Value as ProtoValue;
 use serde::{Deserialize, Serialize};
 
 use super::Operation;
@@ -9,8 +10,13 @@ use crate::proxy::{
     request_headers::RequestHeaders,
     HttpAuthThreescale,
 };
+use crate::util::jwt;
 
 const METADATA: &[&str] = &["metadata"];
+const DEFAULT_JWT_HEADER: &str = "authorization";
+const DEFAULT_JWT_CLAIMS: &[&str] = &["azp", "client_id"];
+const DEFAULT_BASIC_AUTH_HEADER: &str = "authorization";
+const DEFAULT_BODY_MAX_SIZE: usize = 16 * 1024;
 //TODO static METADATA_VEC: Vec<&str> = METADATA.into(); // via lazy_static or some similar mechanism
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,9 +40,70 @@ pub enum Source {
         #[serde(skip_serializing_if = "Option::is_none")]
         ops: Option<Vec<Operation>>,
     },
+    // fields of JSON or form encoded request bodies
+    Body {
+        keys: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_size: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+    },
+    // claims of a JWT whose signature was verified by an earlier filter
+    Jwt {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        header: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        keys: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        issuer: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        audiences: Option<Vec<String>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        check_expiration: Option<bool>,
+        #[serde(default)]
+        signature_delegated: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+    },
+    // base64 encoded "app_id:app_key" pair of HTTP Basic authentication
+    BasicAuth {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        header: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+    },
+    // named cookies, optionally holding form encoded values with the credential under a sub key
+    Cookie {
+        keys: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sub_key: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ops: Option<Vec<Operation>>,
+    },
 }
 
 impl Source {
+    // name of the kind of source, for telling clients where credentials were found
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Source::Header { .. } => "header",
+            Source::Jwt { .. } => "bearer",
+            Source::BasicAuth { .. } => "basic",
+            Source::QueryString { .. } => "query",
+            Source::Filter { .. } => "filter",
+            Source::Body { .. } => "body",
+            Source::Cookie { .. } => "cookie",
+        }
+    }
+
+    // bytes of the request body to buffer for looking up this source, if any
+    pub fn body_limit(&self) -> Option<usize> {
+        match self {
+            Source::Body { max_size, .. } => Some(max_size.unwrap_or(DEFAULT_BODY_MAX_SIZE)),
+            _ => None,
+        }
+    }
+
     pub fn resolve<'url, 'a: 'url>(
         &'a self,
         ctx: &'a HttpAuthThreescale,
@@ -88,62 +155,20 @@ impl Source {
 
                     if let Ok(metadata) = proto {
                         debug!("parsed global metadata as ok");
-                        let r = metadata
-                        .lookup(path[0], &path[1..])
-                        .map(|(v, _segment)| {
-                            keys.iter().find_map(|&k| v.lookup(&[k]).ok())
-                                .map(|(v, segment)| {
-                                    v.as_str().map(|s| vec![s])
-                                        .or_else(|| v.as_list().and_then(|l|
-                                            l.values.iter().try_fold(vec![], |mut acc, v| {
-                                                v.as_str()
-                                                    .map(|s| {
-                                                        acc.push(s);
-                                                        acc
-                                                    })
-                                            })
-                                        ))
-                                        .or_else(|| v.as_struct().and_then(|st| {
-                                            if st.fields.len() == 1 {
-                                                st.fields.values().next().and_then(|v| {
-                                                    v.as_str().map(|s| vec![s])
-                                                        .or_else(|| v.as_list().and_then(|l|
-                                                            l.values.iter().try_fold(vec![], |mut acc, v| {
-                                                            v.as_str()
-                                                                .map(|s| {
-                                                                    acc.push(s);
-                                                                    acc
-                                                                })
-                                                            })
-                                                        ))
-                                                })
-                                            } else {
-                                                None
-                                            }
-                                        }))
-                                    .ok_or_else(|| {
-                                        format!("a string, non empty list of strings, or one-element struct mapping to a string is needed to obtain a value - got a {} at {}", v.kind().as_str(), segment)
-                                    })
-                                })
-                            });
-
-                        // no flatten in stable yet :/
-                        match r {
+                        match filter_values(&metadata, path.as_slice(), keys.as_slice()) {
                             // must own the string, as it references the property vec
-                            Ok(Some(Ok(v))) => Some((
+                            Ok(Some(v)) => Some((
                                 v.into_iter().map(|s| Cow::from(s.to_string())).collect(),
                                 ops,
                             )),
-                            Err(e) => {
-                                debug!("failed to fetch metadata: {}", e);
-                                None
-                            }
-                            Ok(Some(Err(e))) => {
-                                debug!("failed to fetch metadata: {}", e);
+                            // absent entries fall through to the next source
+                            Ok(None) => {
+                                debug!("failed to fetch metadata at {}", path_s);
                                 None
                             }
-                            Ok(None) => {
-                                debug!("failed to fetch metadata");
+                            // present, but not usable as a credential, which is likely a misconfiguration
+                            Err(e) => {
+                                error!("rejecting metadata value at {}: {}", path_s, e);
                                 None
                             }
                         }
@@ -156,6 +181,109 @@ impl Source {
                     None
                 }
             }
+            Source::Body { keys, ops, .. } => {
+                // only available once the body has been buffered
+                let body = ctx.request_body()?;
+                let content_type = rh.get("content-type");
+                match keys
+                    .iter()
+                    .find_map(|key| body_value(content_type, body, key).transpose())
+                {
+                    Some(Ok(value)) => Some((vec![Cow::from(value)], ops)),
+                    // present, but not usable as a credential, which is likely a misconfiguration
+                    Some(Err(e)) => {
+                        error!("rejecting request body value: {}", e);
+                        None
+                    }
+                    None => {
+                        debug!("no request body field found among {:?}", keys);
+                        None
+                    }
+                }
+            }
+            Source::Jwt {
+                header,
+                keys,
+                issuer,
+                audiences,
+                check_expiration,
+                signature_delegated,
+                ops,
+            } => {
+                // configurations not delegating signatures are rejected when loaded
+                if !signature_delegated {
+                    return None;
+                }
+                let header = header.as_deref().unwrap_or(DEFAULT_JWT_HEADER);
+                let token = jwt::strip_bearer(rh.get(header)?);
+                let now = if check_expiration.unwrap_or(true) {
+                    ctx.get_current_time()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .ok()
+                        .map(|now| now.as_secs())
+                } else {
+                    None
+                };
+                let claims = jwt::payload(token).and_then(|payload| {
+                    jwt::validate(&payload, issuer.as_deref(), audiences.as_deref(), now)
+                        .map(|_| payload)
+                });
+                let claims = match claims {
+                    Ok(claims) => claims,
+                    Err(e) => {
+                        debug!("rejecting JWT in header {}: {}", header, e);
+                        return None;
+                    }
+                };
+
+                let mut keys = keys.iter().map(String::as_str).collect::<Vec<_>>();
+                if keys.is_empty() {
+                    keys.extend_from_slice(DEFAULT_JWT_CLAIMS);
+                }
+                let value = keys
+                    .iter()
+                    .map(|&key| jwt::claim(&claims, key))
+                    .find_map(Result::transpose);
+                match value {
+                    // must own the string, as it references the decoded claims
+                    Some(Ok(value)) => Some((vec![Cow::from(value.to_string())], ops)),
+                    // present, but not usable as a credential, which is likely a misconfiguration
+                    Some(Err(e)) => {
+                        error!("rejecting JWT claim: {}", e);
+                        None
+                    }
+                    None => {
+                        debug!("no JWT claim found among {:?}", keys);
+                        None
+                    }
+                }
+            }
+            Source::BasicAuth { header, ops } => {
+                let header = header.as_deref().unwrap_or(DEFAULT_BASIC_AUTH_HEADER);
+                let value = rh.get(header)?;
+                match basic_credentials(value) {
+                    Some((app_id, app_key)) => {
+                        let mut values = vec![Cow::from(app_id)];
+                        values.extend(app_key.map(Cow::from));
+                        Some((values, ops))
+                    }
+                    None => {
+                        debug!("no basic credentials found in header {}", header);
+                        None
+                    }
+                }
+            }
+            Source::Cookie { keys, sub_key, ops } => {
+                keys.iter().map(String::as_str).find_map(|key| {
+                    let value = rh.get_cookie_from_header("cookie", key).flatten()?;
+                    match sub_key {
+                        Some(sub_key) => {
+                            cookie_sub_value(value, sub_key).map(|v| (vec![Cow::from(v)], ops))
+                        }
+                        None => Some((vec![Cow::from(value)], ops)),
+                    }
+                })
+            }
         };
 
         res.and_then(|(values, ops)| {
@@ -167,3 +295,259 @@ impl Source {
         })
     }
 }
+
+// Looks up the first of the keys found within the path of the filter metadata,
+// whose value must be a string, a non empty list of strings, or a one-element
+// struct mapping to one of those. Absent entries are None, so that the next
+// source is tried, whereas present values of other kinds are errors.
+fn filter_values<'m>(
+    metadata: &'m Metadata,
+    path: &[&str],
+    keys: &[&str],
+) -> Result<Option<Vec<&'m str>>, String> {
+    fn strings(v: &ProtoValue) -> Option<Vec<&str>> {
+        v.as_str().map(|s| vec![s]).or_else(|| {
+            v.as_list().and_then(|l| {
+                l.values.iter().try_fold(vec![], |mut acc, v| {
+                    v.as_str().map(|s| {
+                        acc.push(s);
+                        acc
+                    })
+                })
+            })
+        })
+    }
+
+    let value = match metadata.lookup(path[0], &path[1..]) {
+        Ok((v, _segment)) => keys.iter().find_map(|&k| v.lookup(&[k]).ok()),
+        Err(e) => {
+            debug!("failed to fetch metadata: {}", e);
+            return Ok(None);
+        }
+    };
+    let (v, segment) = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    strings(v)
+        .or_else(|| {
+            v.as_struct().and_then(|st| {
+                if st.fields.len() == 1 {
+                    st.fields.values().next().and_then(strings)
+                } else {
+                    None
+                }
+            })
+        })
+        .map(Some)
+        .ok_or_else(|| {
+            format!("a string, non empty list of strings, or one-element struct mapping to a string is needed to obtain a value - got a {} at {}", v.kind().as_str(), segment)
+        })
+}
+
+// Decodes the credentials of a Basic authorization header value, splitting
+// them on the first colon. An empty password means there is no app_key.
+fn basic_credentials(value: &str) -> Option<(String, Option<String>)> {
+    const BASIC: &str = "basic ";
+
+    let encoded = match value.get(..BASIC.len()) {
+        Some(scheme) if scheme.eq_ignore_ascii_case(BASIC) => value[BASIC.len()..].trim(),
+        _ => return None,
+    };
+    let decoded = base64::decode(encoded).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    let app_id = parts.next().filter(|id| !id.is_empty())?.to_string();
+    let app_key = parts
+        .next()
+        .filter(|key| !key.is_empty())
+        .map(str::to_string);
+
+    Some((app_id, app_key))
+}
+
+// Looks up a sub key of a cookie value holding form encoded pairs.
+fn cookie_sub_value(value: &str, sub_key: &str) -> Option<String> {
+    url::form_urlencoded::parse(value.as_bytes())
+        .find(|(k, _)| k == sub_key)
+        .map(|(_, v)| v.into_owned())
+        .filter(|v| !v.is_empty())
+}
+
+// Looks up a field of a JSON body, by name or by a JSON pointer when starting
+// with a slash, or of a form encoded body, as told by the content type.
+fn body_value(
+    content_type: Option<&str>,
+    body: &[u8],
+    key: &str,
+) -> Result<Option<String>, String> {
+    let content_type = content_type
+        .and_then(|ct| ct.split(';').next())
+        .map(|ct| ct.trim().to_ascii_lowercase());
+
+    match content_type.as_deref() {
+        Some("application/x-www-form-urlencoded") => Ok(url::form_urlencoded::parse(body)
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())),
+        Some(ct) if ct == "application/json" || ct.ends_with("+json") => {
+            let json = serde_json::from_slice::<serde_json::Value>(body)
+                .map_err(|e| format!("invalid JSON body: {}", e))?;
+            let value = if key.starts_with('/') {
+                json.pointer(key)
+            } else {
+                json.get(key)
+            };
+            match value {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(serde_json::Value::String(s)) => Ok(Some(s.clone())),
+                Some(_) => Err(format!("field {} is not a string", key)),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn looks_up_body_fields() {
+        let json = br#"{ "api_key": "secret", "app": { "id": "nested" }, "n": 1 }"#;
+        let json_ct = Some("application/json; charset=utf-8");
+
+        assert_eq!(
+            body_value(json_ct, json, "api_key"),
+            Ok(Some("secret".to_string()))
+        );
+        assert_eq!(
+            body_value(json_ct, json, "/app/id"),
+            Ok(Some("nested".to_string()))
+        );
+        assert_eq!(body_value(json_ct, json, "missing"), Ok(None));
+        assert!(body_value(json_ct, json, "n").is_err());
+
+        let form = b"user=a&api_key=s%20cret";
+        let form_ct = Some("application/x-www-form-urlencoded");
+        assert_eq!(
+            body_value(form_ct, form, "api_key"),
+            Ok(Some("s cret".to_string()))
+        );
+        assert_eq!(body_value(form_ct, form, "missing"), Ok(None));
+        assert_eq!(body_value(Some("text/plain"), form, "api_key"), Ok(None));
+    }
+
+    fn claims() -> Metadata {
+        use prost_types::{value::Kind, Struct};
+
+        let value = |kind| ProtoValue { kind: Some(kind) };
+        let fields = |entries: Vec<(&str, ProtoValue)>| Struct {
+            fields: entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        };
+        let claims = fields(vec![
+            ("azp", value(Kind::StringValue("my-app".into()))),
+            ("exp", value(Kind::NumberValue(10.0))),
+        ]);
+        Metadata::from_filter(
+            "envoy.filters.http.jwt_authn",
+            fields(vec![("verified", value(Kind::StructValue(claims)))]),
+        )
+    }
+
+    #[test]
+    fn falls_through_absent_filter_metadata() {
+        let metadata = claims();
+        let path = ["envoy.filters.http.jwt_authn", "verified"];
+
+        assert_eq!(
+            filter_values(&metadata, &path, &["client_id", "azp"]),
+            Ok(Some(vec!["my-app"]))
+        );
+        assert_eq!(filter_values(&metadata, &path, &["client_id"]), Ok(None));
+        assert_eq!(
+            filter_values(
+                &metadata,
+                &["envoy.filters.http.jwt_authn", "missing"],
+                &["azp"]
+            ),
+            Ok(None)
+        );
+        assert_eq!(
+            filter_values(&metadata, &["other.filter", "verified"], &["azp"]),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn rejects_filter_metadata_of_other_kinds() {
+        let metadata = claims();
+        let path = ["envoy.filters.http.jwt_authn", "verified"];
+
+        assert!(filter_values(&metadata, &path, &["exp"]).is_err());
+    }
+
+    #[test]
+    fn decodes_basic_credentials() {
+        // "aladdin:opensesame"
+        assert_eq!(
+            basic_credentials("Basic YWxhZGRpbjpvcGVuc2VzYW1l"),
+            Some(("aladdin".to_string(), Some("opensesame".to_string())))
+        );
+        // "app:" and "app:key:with:colons"
+        assert_eq!(
+            basic_credentials("basic YXBwOg=="),
+            Some(("app".to_string(), None))
+        );
+        assert_eq!(
+            basic_credentials("BASIC YXBwOmtleTp3aXRoOmNvbG9ucw=="),
+            Some(("app".to_string(), Some("key:with:colons".to_string())))
+        );
+        assert_eq!(basic_credentials("Bearer YWxhZGRpbjpvcGVuc2VzYW1l"), None);
+        assert_eq!(basic_credentials("Basic not-base64!"), None);
+    }
+
+    #[test]
+    fn tells_kinds_of_header_sources_apart() {
+        let header = Source::Header {
+            keys: vec!["x-key".to_string()],
+            ops: None,
+        };
+        let jwt = Source::Jwt {
+            header: None,
+            keys: Vec::new(),
+            issuer: None,
+            audiences: None,
+            check_expiration: None,
+            signature_delegated: true,
+            ops: None,
+        };
+        let basic = Source::BasicAuth {
+            header: None,
+            ops: None,
+        };
+
+        assert_eq!(header.kind(), "header");
+        assert_eq!(jwt.kind(), "bearer");
+        assert_eq!(basic.kind(), "basic");
+    }
+
+    #[test]
+    fn looks_up_cookie_sub_keys() {
+        let value = "app_id=my%20app&app_key=secret&empty=";
+
+        assert_eq!(
+            cookie_sub_value(value, "app_id"),
+            Some("my app".to_string())
+        );
+        assert_eq!(
+            cookie_sub_value(value, "app_key"),
+            Some("secret".to_string())
+        );
+        assert_eq!(cookie_sub_value(value, "empty"), None);
+        assert_eq!(cookie_sub_value(value, "missing"), None);
+    }
+}
@@ -0,0 +1,496 @@
+// Validation of plugin configurations as a whole.
+//
+// Deserializing a configuration stops at the first error and silently skips
+// fields it does not know about, so configurations are also checked as plain
+// documents to report all the problems found at once. Each one is located
+// with a JSON pointer (RFC 6901) into the document. Unknown fields are only
+// warned about, since configurations are compared with their serialized form,
+// which does not round trip every way of writing them.
+use core::convert::TryFrom;
+
+use serde_json::Value;
+
+use super::{Configuration, MissingError};
+use crate::upstream::Builder;
+use crate::util::glob::GlobPattern;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pointer: String,
+    message: String,
+    // warnings don't get the configuration rejected
+    warning: bool,
+}
+
+impl Diagnostic {
+    fn new(pointer: String, message: impl ToString) -> Self {
+        Self {
+            pointer,
+            message: message.to_string(),
+            warning: false,
+        }
+    }
+
+    fn warning(pointer: String, message: impl ToString) -> Self {
+        Self {
+            warning: true,
+            ..Self::new(pointer, message)
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.warning
+    }
+}
+
+impl core::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}: {}", self.pointer, self.message)
+    }
+}
+
+// Parses the configuration as a plain document, reporting syntax errors.
+#[cfg(any(feature = "json_config", not(feature = "yaml_config")))]
+pub fn document(buf: &[u8]) -> Result<Value, serde_json::Error> {
+    serde_json::from_slice(buf)
+}
+
+#[cfg(all(
+    feature = "yaml_config",
+    feature = "danger",
+    not(feature = "json_config")
+))]
+pub fn document(buf: &[u8]) -> Result<Value, serde_yaml::Error> {
+    serde_yaml::from_slice(buf)
+}
+
+fn pointer(base: &str, segment: impl ToString) -> String {
+    let segment = segment.to_string().replace('~', "~0").replace('/', "~1");
+    format!("{}/{}", base, segment)
+}
+
+// Entries of an array field along with their pointers.
+fn entries<'a>(value: &'a Value, base: &str, field: &str) -> Vec<(String, &'a Value)> {
+    let base = pointer(base, field);
+    value
+        .get(field)
+        .and_then(Value::as_array)
+        .map(|values| {
+            values
+                .iter()
+                .enumerate()
+                .map(|(idx, value)| (pointer(&base, idx), value))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn check_upstream(value: &Value, base: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let url = match value.pointer("/upstream/url").and_then(Value::as_str) {
+        Some(url) => url,
+        None => return,
+    };
+    let at = pointer(&pointer(base, "upstream"), "url");
+    match url::Url::parse(url) {
+        Ok(parsed) => {
+            if let Err(e) = Builder::try_from(parsed) {
+                diagnostics.push(Diagnostic::new(at, format!("invalid url {}: {}", url, e)));
+            }
+        }
+        Err(e) => diagnostics.push(Diagnostic::new(at, format!("invalid url {}: {}", url, e))),
+    }
+}
+
+fn check_globs(values: Vec<(String, &Value)>, diagnostics: &mut Vec<Diagnostic>) {
+    for (at, value) in values {
+        if let Some(glob) = value.as_str() {
+            if let Err(e) = GlobPattern::new(glob) {
+                diagnostics.push(Diagnostic::new(
+                    at,
+                    format!("invalid glob pattern {}: {}", glob, e),
+                ));
+            }
+        }
+    }
+}
+
+fn check_token(value: &Value, at: String, diagnostics: &mut Vec<Diagnostic>) {
+    if value.get("token").and_then(Value::as_str) == Some("") {
+        diagnostics.push(Diagnostic::new(pointer(&at, "token"), "empty token"));
+    }
+}
+
+// This module does not verify signatures of JWTs, so an earlier filter must.
+fn check_jwt_sources(credentials: &Value, at: &str, diagnostics: &mut Vec<Diagnostic>) {
+    for kind in &["user_key", "app_id", "app_key"] {
+        for (source_at, source) in entries(credentials, at, kind) {
+            let jwt = match source.get("jwt") {
+                Some(jwt) if !jwt.is_null() => jwt,
+                _ => continue,
+            };
+            if jwt.get("signature_delegated").and_then(Value::as_bool) != Some(true) {
+                diagnostics.push(Diagnostic::new(
+                    pointer(&pointer(&source_at, "jwt"), "signature_delegated"),
+                    "JWT signatures are not verified, set to true once an earlier filter verifies them",
+                ));
+            }
+        }
+    }
+}
+
+// Parameters of the 3scale API, which passed through parameters would clash with.
+const RESERVED_PARAMS: &[&str] = &[
+    "service_id",
+    "service_token",
+    "provider_key",
+    "access_token",
+    "user_key",
+    "app_id",
+    "app_key",
+    "user_id",
+    "usage",
+    "log",
+];
+
+fn check_passthrough_params(values: Vec<(String, &Value)>, diagnostics: &mut Vec<Diagnostic>) {
+    for (at, param) in values {
+        // passed through with the name of the query string parameter by default
+        let field = if param.get("name").map_or(false, |name| !name.is_null()) {
+            "name"
+        } else {
+            "query_string"
+        };
+        let name = match param.get(field).and_then(Value::as_str) {
+            Some(name) => name,
+            None => continue,
+        };
+        let reserved = RESERVED_PARAMS
+            .iter()
+            .any(|&reserved| name == reserved || name.starts_with(&format!("{}[", reserved)));
+        if reserved {
+            diagnostics.push(Diagnostic::new(
+                pointer(&at, field),
+                format!("{} is a parameter of the 3scale API", name),
+            ));
+        }
+    }
+}
+
+// Fields of the document missing in the serialized configuration are unknown
+// to it. Null values are left out, as they have the same effect as missing ones.
+fn unknown_fields(value: &Value, known: &Value, at: &str, diagnostics: &mut Vec<Diagnostic>) {
+    match (value, known) {
+        (Value::Object(fields), Value::Object(known_fields)) => {
+            for (name, value) in fields.iter().filter(|(_, value)| !value.is_null()) {
+                let at = pointer(at, name);
+                match known_fields.get(name) {
+                    Some(known) => unknown_fields(value, known, &at, diagnostics),
+                    None => diagnostics.push(Diagnostic::warning(at, "unknown field")),
+                }
+            }
+        }
+        (Value::Array(values), Value::Array(known_values)) => {
+            for (idx, (value, known)) in values.iter().zip(known_values).enumerate() {
+                unknown_fields(value, known, &pointer(at, idx), diagnostics);
+            }
+        }
+        _ => (),
+    }
+}
+
+// Checks the document, and the configuration deserialized from it if that
+// succeeded, returning all the problems found.
+pub fn validate(document: &Value, config: Option<&Configuration>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let system = document.get("system").filter(|system| !system.is_null());
+    if let Some(system) = system {
+        check_upstream(system, "/system", &mut diagnostics);
+        check_token(system, "/system".to_string(), &mut diagnostics);
+    }
+    // batched usage is flushed on ticks, and a tick period of zero stops them
+    if document
+        .pointer("/batching/flush_interval")
+        .and_then(Value::as_u64)
+        == Some(0)
+    {
+        diagnostics.push(Diagnostic::new(
+            "/batching/flush_interval".to_string(),
+            "must be at least 1 second",
+        ));
+    }
+    for field in &["backend", "shadow_backend"] {
+        if let Some(backend) = document.get(field) {
+            check_upstream(backend, &pointer("", field), &mut diagnostics);
+        }
+    }
+    let backends = entries(document, "", "backends");
+    for (at, backend) in backends.iter() {
+        check_upstream(backend, at, &mut diagnostics);
+    }
+    // services can name the top level backend as well as the others
+    let backend_names = document
+        .get("backend")
+        .into_iter()
+        .chain(backends.iter().map(|(_, backend)| *backend))
+        .filter_map(|backend| backend.get("name").and_then(Value::as_str))
+        .collect::<Vec<_>>();
+
+    for (at, service) in entries(document, "", "services") {
+        check_upstream(service, &at, &mut diagnostics);
+        check_globs(entries(service, &at, "authorities"), &mut diagnostics);
+        for (rule_at, rule) in entries(
+            service.get("bypass").unwrap_or(&Value::Null),
+            &pointer(&at, "bypass"),
+            "rules",
+        ) {
+            check_globs(entries(rule, &rule_at, "paths"), &mut diagnostics);
+        }
+
+        check_passthrough_params(
+            entries(service, &at, "passthrough_params"),
+            &mut diagnostics,
+        );
+        if let Some(credentials) = service.get("credentials") {
+            check_jwt_sources(credentials, &pointer(&at, "credentials"), &mut diagnostics);
+        }
+
+        match service.get("token") {
+            Some(token) if !token.is_null() => check_token(service, at.clone(), &mut diagnostics),
+            // the token can only be fetched from system
+            _ if system.is_none() => diagnostics.push(Diagnostic::new(
+                pointer(&at, "token"),
+                "missing token, and no system to fetch it from",
+            )),
+            _ => (),
+        }
+
+        if let Some(name) = service.get("backend").and_then(Value::as_str) {
+            if !backend_names.contains(&name) {
+                diagnostics.push(Diagnostic::new(
+                    pointer(&at, "backend"),
+                    MissingError::NamedBackend(name.to_string()),
+                ));
+            }
+        }
+    }
+
+    if let Some(config) = config {
+        match serde_json::to_value(config) {
+            Ok(known) => unknown_fields(document, &known, "", &mut diagnostics),
+            Err(e) => log::debug!("failed to check for unknown fields: {}", e),
+        }
+    }
+
+    diagnostics
+}
+
+#[cfg(all(test, any(feature = "json_config", not(feature = "yaml_config"))))]
+mod test {
+    use super::*;
+
+    fn diagnostics(config: &str) -> Vec<String> {
+        let document = document(config.as_bytes()).unwrap();
+        let config = Configuration::try_from(config.as_bytes()).ok();
+        validate(&document, config.as_ref())
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn reports_all_problems_of_invalid_documents() {
+        let config = r#"{
+          "api": "v1",
+          "backend": { "upstream": { "name": "backend", "url": "not a url" } },
+          "services": [
+            {
+              "id": "1",
+              "authorities": ["*"],
+              "credentials": { "user_key": [{ "header": { "keys": ["x-key"] } }] },
+              "mapping_rules": [],
+              "backend": "missing"
+            }
+          ]
+        }"#;
+
+        assert_eq!(
+            diagnostics(config),
+            vec![
+                "/backend/upstream/url: invalid url not a url: relative URL without a base",
+                "/services/0/token: missing token, and no system to fetch it from",
+                "/services/0/backend: backend missing not configured",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unknown_fields() {
+        let config = r#"{
+          "api": "v1",
+          "backend": {
+            "upstream": { "name": "backend", "url": "https://su1.3scale.net", "timeout": 5000 }
+          },
+          "services": [
+            {
+              "id": "1",
+              "token": "token",
+              "credentials": { "user_key": [{ "header": { "keys": ["x-key"], "ops": null } }] },
+              "mapping_rules": [
+                { "method": "GET", "pattern": "/", "usages": [{ "name": "Hits", "delta": 1 }] }
+              ],
+              "mapping_rule": [],
+              "cache": null
+            }
+          ],
+          "cache": { "ttl": 10, "entries": 100 }
+        }"#;
+
+        // warned about, not rejected
+        assert_eq!(
+            diagnostics(config),
+            vec![
+                "/cache/entries: unknown field",
+                "/services/0/mapping_rule: unknown field",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_passthrough_params_clashing_with_the_api() {
+        let config = r#"{
+          "api": "v1",
+          "backend": {
+            "upstream": { "name": "backend", "url": "https://su1.3scale.net", "timeout": 5000 }
+          },
+          "services": [
+            {
+              "id": "1",
+              "token": "token",
+              "credentials": { "user_key": [{ "header": { "keys": ["x-key"] } }] },
+              "mapping_rules": [],
+              "passthrough_params": [
+                { "query_string": "plan" },
+                { "query_string": "user_key" },
+                { "query_string": "hits", "name": "usage[Hits]" },
+                { "query_string": "app_id", "name": "client" },
+                { "query_string": "usage_plan" }
+              ]
+            }
+          ]
+        }"#;
+
+        assert_eq!(
+            diagnostics(config),
+            vec![
+                "/services/0/passthrough_params/1/query_string: user_key is a parameter of the 3scale API",
+                "/services/0/passthrough_params/2/name: usage[Hits] is a parameter of the 3scale API",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_flushing_batches_continuously() {
+        let config = r#"{
+          "api": "v1",
+          "backend": {
+            "upstream": { "name": "backend", "url": "https://su1.3scale.net", "timeout": 5000 }
+          },
+          "services": [],
+          "batching": { "flush_interval": 0 }
+        }"#;
+
+        assert_eq!(
+            diagnostics(config),
+            vec!["/batching/flush_interval: must be at least 1 second"]
+        );
+    }
+
+    #[test]
+    fn rejects_jwt_sources_not_delegating_signatures() {
+        let config = r#"{
+          "api": "v1",
+          "backend": {
+            "upstream": { "name": "backend", "url": "https://su1.3scale.net", "timeout": 5000 }
+          },
+          "services": [
+            {
+              "id": "1",
+              "token": "token",
+              "credentials": {
+                "app_id": [
+                  { "jwt": { "keys": ["azp"] } },
+                  { "jwt": { "keys": ["client_id"], "signature_delegated": true } }
+                ]
+              },
+              "mapping_rules": []
+            }
+          ]
+        }"#;
+
+        assert_eq!(
+            diagnostics(config),
+            vec!["/services/0/credentials/app_id/0/jwt/signature_delegated: JWT signatures are not verified, set to true once an earlier filter verifies them"]
+        );
+    }
+
+    #[test]
+    fn accepts_services_naming_the_top_level_backend() {
+        let config = r#"{
+          "api": "v1",
+          "backend": {
+            "name": "backend-name",
+            "upstream": { "name": "backend", "url": "https://su1.3scale.net", "timeout": 5000 }
+          },
+          "services": [
+            {
+              "id": "1",
+              "token": "token",
+              "credentials": { "user_key": [{ "header": { "keys": ["x-key"] } }] },
+              "mapping_rules": [],
+              "backend": "backend-name"
+            }
+          ]
+        }"#;
+
+        assert!(diagnostics(config).is_empty());
+    }
+
+    #[test]
+    fn accepts_sample_configurations() {
+        let yaml = |sample: &str| serde_yaml::from_str::<Value>(sample).unwrap();
+        let lds = yaml(include_str!("../../compose/envoy/lds.yaml"));
+        let envoy_filter = yaml(include_str!(
+            "../../servicemesh/crds/istio/envoyfilter.yaml"
+        ));
+        let extension = yaml(include_str!(
+            "../../servicemesh/crds/ossm/servicemeshextension.yaml"
+        ));
+
+        // Envoy takes configurations as JSON strings, while OSSM embeds them
+        let samples = vec![
+            lds.pointer(
+                "/resources/0/filter_chains/0/filters/0/typed_config/http_filters/1/typed_config/config/configuration/value",
+            )
+            .and_then(Value::as_str)
+            .map(str::to_string),
+            envoy_filter
+                .pointer("/spec/configPatches/0/patch/value/typedConfig/value/config/configuration/value")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            extension.pointer("/spec/config").map(Value::to_string),
+        ];
+
+        for sample in samples {
+            let sample = sample.expect("sample configuration not found");
+            let document = document(sample.as_bytes()).unwrap();
+            let config = Configuration::try_from(sample.as_bytes()).unwrap();
+            let errors = validate(&document, Some(&config))
+                .iter()
+                .filter(|diagnostic| !diagnostic.is_warning())
+                .map(ToString::to_string)
+                .collect::<Vec<_>>();
+            assert_eq!(errors, Vec::<String>::new());
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
 use serde::{Deserialize, Serialize};
 
 #[repr(u32)]
@@ -33,6 +35,18 @@ impl From<LogLevel> for proxy_wasm::types::LogLevel {
     }
 }
 
+// Minimum level to log, checked before formatting anything or calling into the host.
+static MIN_LEVEL: AtomicU32 = AtomicU32::new(LogLevel::Trace as u32);
+
+pub fn set_min_level(level: LogLevel) {
+    MIN_LEVEL.store(level as u32, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled(level: LogLevel) -> bool {
+    level as u32 >= MIN_LEVEL.load(Ordering::Relaxed)
+}
+
 pub trait IdentLogger {
     fn ident(&self) -> &str;
 }
@@ -46,7 +60,10 @@ impl<'a> IdentLogger for &'a str {
 #[macro_export(local_inner_macros)]
 macro_rules! log {
     ($ident:expr, $lvl:expr, $($arg:tt)+) => ({
-        $crate::log::with_ident($ident, core::format_args!($($arg)+), $lvl)
+        let lvl = $lvl;
+        if $crate::log::enabled(lvl) {
+            $crate::log::with_ident($ident, core::format_args!($($arg)+), lvl)
+        }
     })
 }
 
@@ -120,3 +137,44 @@ pub fn with_ident(ctx: &dyn IdentLogger, args: core::fmt::Arguments, level: LogL
     )
     .unwrap();
 }
+
+// Compares a call below the minimum level with formatting its message, which
+// calls took before the level was checked. Needs a nightly toolchain:
+// `cargo +nightly bench log`.
+#[cfg(all(test, feature_test))]
+mod bench {
+    extern crate test;
+
+    use super::*;
+    use test::{black_box, Bencher};
+
+    const IDENT: &str = "1234567890 (2/http)";
+
+    #[bench]
+    fn call_below_min_level(b: &mut Bencher) {
+        set_min_level(LogLevel::Info);
+        b.iter(|| {
+            debug!(
+                &IDENT,
+                "matching pat {} against rule {:#?}",
+                black_box("/users/42"),
+                black_box(["GET", "/users/{id}"])
+            )
+        });
+    }
+
+    #[bench]
+    fn formatting_message(b: &mut Bencher) {
+        b.iter(|| {
+            black_box(format!(
+                "{:>22}: {}",
+                IDENT,
+                core::format_args!(
+                    "matching pat {} against rule {:#?}",
+                    black_box("/users/42"),
+                    black_box(["GET", "/users/{id}"])
+                )
+            ))
+        });
+    }
+}
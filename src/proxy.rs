@@ -1,7 +1,15 @@
 mod authrep;
+mod batch;
+mod cache;
+mod coalesce;
 pub mod config_fetcher;
+mod decision;
+mod limits;
 pub mod metadata;
+mod metrics;
 pub mod request_headers;
+mod signature;
+mod verdict;
 
 mod http_context;
 pub use http_context::HttpAuthThreescale;
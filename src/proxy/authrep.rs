@@ -1,8 +1,13 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 
 use super::request_headers::RequestHeaders;
 use super::HttpAuthThreescale;
-use crate::threescale::CredentialsError;
+use crate::log::IdentLogger;
+use crate::threescale::{
+    CredentialsError, MappingRule, NoMatchBehavior, ParamPassthrough, UsageMerge,
+};
+use crate::upstream::Upstream;
 use threescalers::{
     api_call::{ApiCall, Kind},
     application::Application,
@@ -14,20 +19,29 @@ use threescalers::{
 };
 
 #[derive(Debug, thiserror::Error)]
-enum MatchError {
-    #[error("no known service matched")]
-    NoServiceMatched,
-    #[error("credentials error")]
-    CredentialsError(#[from] CredentialsError),
-    #[error("no usage match")]
-    NoUsageMatch,
+pub enum MatchError {
+    #[error("no known service matched authority {0}")]
+    NoServiceMatched(String),
+    // carries the id of the matched service
+    #[error("credentials error in service {0}")]
+    CredentialsError(String, #[source] CredentialsError),
+    // carries the id of the matched service
+    #[error("no usage match in service {0}")]
+    NoUsageMatch(String),
 }
 
 #[derive(Debug, Clone)]
 pub struct AuthRep<'a> {
     service: &'a crate::threescale::Service,
     apps: Vec<Application>,
-    usages: HashMap<&'a str, i64>,
+    source: Option<&'a crate::configuration::Source>,
+    usages: HashMap<Cow<'a, str>, i64>,
+    params: Vec<(&'a str, String)>,
+    response_metrics: Vec<&'a str>,
+    metered: bool,
+    batched: bool,
+    // patterns of the mapping rules matched
+    matched_rules: Vec<String>,
 }
 
 impl<'a> AuthRep<'a> {
@@ -39,9 +53,174 @@ impl<'a> AuthRep<'a> {
         &self.apps
     }
 
-    pub fn usages(&self) -> &HashMap<&'a str, i64> {
+    pub fn usages(&self) -> &HashMap<Cow<'a, str>, i64> {
         &self.usages
     }
+
+    pub fn matched_rules(&self) -> &[String] {
+        self.matched_rules.as_slice()
+    }
+
+    pub fn is_metered(&self) -> bool {
+        self.metered
+    }
+
+    // whether usage is reported after the response rather than with the authorization
+    pub fn is_deferred(&self) -> bool {
+        self.metered && self.service.report_on_response()
+    }
+
+    // whether usage is queued to be reported in batches rather than with the authorization
+    pub fn is_batched(&self) -> bool {
+        self.metered && self.batched && !self.is_deferred()
+    }
+
+    // unmetered apps are only authorized, their usage is never reported
+    pub fn kind(&self) -> Kind {
+        if self.metered && !self.is_deferred() && !self.is_batched() {
+            Kind::AuthRep
+        } else {
+            Kind::Authorize
+        }
+    }
+
+    // Extra query string parameters to be appended to the call to 3scale.
+    pub fn extra_query_string(&self) -> Option<String> {
+        if self.params.is_empty() {
+            return None;
+        }
+
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        serializer.extend_pairs(self.params.iter().map(|(k, v)| (*k, v.as_str())));
+        Some(serializer.finish())
+    }
+
+    pub fn state(&self) -> Option<AuthState> {
+        self.apps.first().map(|app| AuthState {
+            service_id: self.service.id().to_string(),
+            app: app.clone(),
+            source: self
+                .source
+                .filter(|_| self.service.auth_source_header())
+                .map(|source| source.kind()),
+            deferred_usage: if self.is_deferred() {
+                self.usages
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), format!("{}", v)))
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            batched_usage: if self.is_batched() {
+                self.usages
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), *v))
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            response_metrics: if self.metered {
+                self.response_metrics
+                    .iter()
+                    .map(|metric| metric.to_string())
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            metered: self.metered,
+        })
+    }
+}
+
+// Owned details of an AuthRep needed in the later phases of a request.
+#[derive(Debug, Clone)]
+pub struct AuthState {
+    service_id: String,
+    app: Application,
+    source: Option<&'static str>,
+    deferred_usage: Vec<(String, String)>,
+    batched_usage: Vec<(String, i64)>,
+    response_metrics: Vec<String>,
+    metered: bool,
+}
+
+impl AuthState {
+    pub fn service_id(&self) -> &str {
+        self.service_id.as_str()
+    }
+
+    pub fn app(&self) -> &Application {
+        &self.app
+    }
+
+    // kind of source the credentials were found in, if it should be told to clients
+    pub fn source(&self) -> Option<&'static str> {
+        self.source
+    }
+
+    // usage to report once the upstream has responded successfully
+    pub fn deferred_usage(&self) -> &[(String, String)] {
+        self.deferred_usage.as_slice()
+    }
+
+    // usage to queue for batch reporting once the request is authorized
+    pub fn batched_usage(&self) -> &[(String, i64)] {
+        self.batched_usage.as_slice()
+    }
+
+    // metrics to report the response body size with once the response is complete
+    pub fn response_metrics(&self) -> &[String] {
+        self.response_metrics.as_slice()
+    }
+
+    pub fn app_id(&self) -> &str {
+        app_identifier(&self.app)
+    }
+
+    pub const fn is_metered(&self) -> bool {
+        self.metered
+    }
+}
+
+// identifier of the application, whatever kind of credentials it uses
+fn app_identifier(app: &Application) -> &str {
+    match app {
+        Application::AppId(app_id, _) => app_id.as_ref(),
+        Application::UserKey(user_key) => user_key.as_ref(),
+        Application::OAuthToken(token) => token.as_ref(),
+    }
+}
+
+// values are percent-decoded here and encoded back when building the call
+fn query_param(url: &url::Url, name: &str) -> Option<String> {
+    url.query_pairs().find_map(|(k, v)| {
+        if k == name {
+            Some(v.into_owned())
+        } else {
+            None
+        }
+    })
+}
+
+fn passthrough_params<'a>(
+    ctx: &HttpAuthThreescale,
+    params: &'a [ParamPassthrough],
+    url: &url::Url,
+) -> Vec<(&'a str, String)> {
+    params
+        .iter()
+        .filter_map(|param| {
+            let value = query_param(url, param.query_string());
+            if value.is_none() {
+                debug!(
+                    ctx,
+                    "query string parameter {} not found, not passing it through",
+                    param.query_string()
+                );
+            }
+            value.map(|v| (param.name(), v))
+        })
+        .collect()
 }
 
 #[allow(dead_code)]
@@ -50,33 +229,115 @@ pub fn request(ctx: &HttpAuthThreescale, rh: &RequestHeaders) -> Result<Request,
     build_call(&ar)
 }
 
+// Finds the service of the request: the most specific match wins, and ties
+// go to the service listed first.
+pub fn find_service<'a>(
+    ctx: &'a HttpAuthThreescale,
+    rh: &RequestHeaders,
+) -> Result<&'a crate::threescale::Service, anyhow::Error> {
+    let config = ctx.configuration();
+    let svclist = config.get_services()?;
+    let environment = config.environment();
+    let url = rh.url()?;
+    let authority = url.authority();
+
+    let service = svclist
+        .iter()
+        .filter(|svc| svc.is_in_environment(environment))
+        .filter_map(|svc| svc.authority_specificity(authority).map(|spec| (spec, svc)))
+        .fold(None, |best, (spec, svc)| match best {
+            Some((best_spec, _)) if best_spec >= spec => best,
+            _ => Some((spec, svc)),
+        })
+        .map(|(_, svc)| svc)
+        .ok_or_else(|| MatchError::NoServiceMatched(authority.to_string()))?;
+
+    Ok(service)
+}
+
 pub fn authrep<'a>(
     ctx: &'a HttpAuthThreescale,
     rh: &'a RequestHeaders,
 ) -> Result<AuthRep<'a>, anyhow::Error> {
     let config = ctx.configuration();
-    let svclist = config.get_services()?;
+    let service = find_service(ctx, rh)?;
 
     let metadata = rh.metadata();
-    let method = Method::from(metadata.method());
+    // gRPC calls are all POSTs, whatever the request might claim
+    let method = if service.is_grpc() {
+        Method::POST
+    } else {
+        Method::from(metadata.method())
+    };
     let url = rh.url()?;
-    let authority = url.authority();
     let path = url.path();
-    let mut pattern = path.to_string();
     let qs = url.query();
+
+    // the forwarded request keeps its path, only matching uses the stripped one
+    let mut pattern = if service.strip_matrix_params() {
+        strip_matrix_params(path).into_owned()
+    } else {
+        path.to_string()
+    };
     if let Some(qs) = qs {
         pattern.push('?');
         pattern.push_str(qs);
     }
 
-    let service = svclist
-        .iter()
-        .find(|&svc| svc.match_authority(authority))
-        .ok_or(MatchError::NoServiceMatched)?;
+    // rules are matched before looking up credentials, as unmatched requests
+    // might be let through without them
+    let plan = service
+        .plan()
+        .and_then(|sources| {
+            sources
+                .iter()
+                .find_map(|source| source.resolve(ctx, rh, &url))
+        })
+        .and_then(|values| values.into_iter().next());
+    if let Some(plan) = plan.as_ref() {
+        debug!(ctx, "application is on plan {}", plan);
+    }
+
+    let (mut usages, response_metrics, matched_rules) = match_rules(
+        ctx,
+        service.mapping_rules(),
+        &method,
+        pattern.as_str(),
+        plan.as_deref(),
+        service.usage_merge(),
+    );
+
+    if usages.is_empty() {
+        if let Some(template) = service.method_metric() {
+            let metric = template.replace("{method}", &metadata.method().to_ascii_lowercase());
+            debug!(
+                ctx,
+                "no mapping rule matched, using metric {} for the request method", metric
+            );
+            usages.insert(Cow::from(metric), 1);
+        } else if let Some(NoMatchBehavior::ReportDefaultMetric { metric }) =
+            service.no_match_behavior()
+        {
+            debug!(
+                ctx,
+                "no mapping rule matched, using default metric {}", metric
+            );
+            usages.insert(Cow::from(metric.as_str()), 1);
+        }
+    }
+
+    if usages.is_empty() {
+        anyhow::bail!(MatchError::NoUsageMatch(service.id().to_string()));
+    }
 
     let credentials = service.credentials();
 
-    let apps = credentials.resolve(ctx, rh, &url)?;
+    let (apps, sources): (Vec<_>, Vec<_>) = credentials
+        .resolve(ctx, rh, &url)
+        .map_err(|e| MatchError::CredentialsError(service.id().to_string(), e))?
+        .into_iter()
+        .unzip();
+    let source = sources.first().copied();
 
     debug!(ctx, "found credentials, values {:#?}", apps);
     if apps.len() > 1 {
@@ -86,19 +347,153 @@ pub fn authrep<'a>(
         );
     }
 
-    let mut usages = std::collections::HashMap::new();
-    for rule in service.mapping_rules() {
-        debug!(
-            ctx,
-            "matching pat {} against rule {:#?}",
-            pattern.as_str(),
-            rule
-        );
-        if rule.is_match(&method, pattern.as_str()) {
+    let metered = !apps
+        .first()
+        .map_or(false, |app| service.is_unmetered(app_identifier(app)));
+    if !metered {
+        debug!(ctx, "application is unmetered, usage will not be reported");
+    }
+
+    let params = service
+        .passthrough_params()
+        .map(|params| passthrough_params(ctx, params.as_slice(), &url))
+        .unwrap_or_default();
+
+    Ok(AuthRep {
+        service,
+        apps,
+        source,
+        usages,
+        params,
+        response_metrics,
+        metered,
+        batched: config.batching().is_some(),
+        matched_rules,
+    })
+}
+
+pub const OPTIONS_HEADER: &str = "3scale-options";
+
+// Asks 3scale for the reason of rejections and for the state of the limits.
+pub const EXTRA_OPTIONS: &str = "rejection_reason_header=1&limit_headers=1";
+
+// Value of the options header with the extra options added to any existing ones.
+pub fn merged_options(headers: &[(&str, &str)], extra: &str) -> String {
+    match headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(OPTIONS_HEADER))
+    {
+        Some((_, options)) if !options.is_empty() => format!("{}&{}", options, extra),
+        _ => extra.to_string(),
+    }
+}
+
+// Headers passing the 3scale information on to the next filter in the chain.
+pub fn passthrough_headers(
+    ar: &AuthRep,
+    upstream: &Upstream,
+) -> Result<Vec<(&'static str, String)>, anyhow::Error> {
+    let apps = ar.apps();
+    let service = ar.service();
+
+    if apps.is_empty() {
+        anyhow::bail!("could not extract application credentials");
+    }
+
+    let app_header = match &apps[0] {
+        Application::AppId(app_id, app_key) => {
+            let mut app_id_key = app_id.as_ref().to_string();
+            if let Some(key) = app_key {
+                app_id_key.push(':');
+                app_id_key.push_str(key.as_ref());
+            }
+            ("x-3scale-app-id", app_id_key)
+        }
+        Application::UserKey(user_key) => ("x-3scale-user-key", user_key.as_ref().to_string()),
+        Application::OAuthToken(token) => ("x-3scale-access-token", token.as_ref().to_string()),
+    };
+
+    // without service token, other info is useless.
+    let service_token = if let Some(token) = service.token() {
+        token
+    } else {
+        anyhow::bail!("service token unavailable");
+    };
+
+    Ok(vec![
+        app_header,
+        ("x-3scale-cluster-name", upstream.name().to_string()),
+        ("x-3scale-upstream-url", upstream.url.as_str().to_string()),
+        ("x-3scale-timeout", upstream.default_timeout().to_string()),
+        ("x-3scale-service-id", service.id().to_string()),
+        ("x-3scale-service-token", service_token.to_string()),
+        ("x-3scale-usages", serde_json::to_string(ar.usages())?),
+    ])
+}
+
+// The same information as the passthrough headers, named after them without
+// their prefix, ie. "service-id".
+pub fn passthrough_properties(
+    ar: &AuthRep,
+    upstream: &Upstream,
+) -> Result<Vec<(&'static str, String)>, anyhow::Error> {
+    Ok(passthrough_headers(ar, upstream)?
+        .into_iter()
+        .map(|(header, value)| (header.trim_start_matches("x-3scale-"), value))
+        .collect())
+}
+
+// Usage of a request skipping authorization, to report for the anonymous application.
+pub fn bypass_usage(
+    ctx: &dyn IdentLogger,
+    service: &crate::threescale::Service,
+    method: &str,
+    pattern: &str,
+) -> Vec<(String, String)> {
+    let method = if service.is_grpc() {
+        Method::POST
+    } else {
+        Method::from(method)
+    };
+    let (usages, _, _) = match_rules(
+        ctx,
+        service.mapping_rules(),
+        &method,
+        pattern,
+        None,
+        service.usage_merge(),
+    );
+    usages
+        .into_iter()
+        .map(|(metric, delta)| (metric.into_owned(), delta.to_string()))
+        .collect()
+}
+
+// Accumulates the usages of all the rules matching both the method and the pattern.
+fn match_rules<'a>(
+    ctx: &dyn IdentLogger,
+    rules: &'a [MappingRule],
+    method: &Method,
+    pattern: &str,
+    plan: Option<&str>,
+    merge: UsageMerge,
+) -> (HashMap<Cow<'a, str>, i64>, Vec<&'a str>, Vec<String>) {
+    let mut usages = HashMap::new();
+    let mut response_metrics = Vec::new();
+    let mut matched = Vec::new();
+    for rule in rules {
+        debug!(ctx, "matching pat {} against rule {:#?}", pattern, rule);
+        if rule.is_match(method, pattern) {
             debug!(ctx, "matched pattern in {}", pattern);
+            matched.push(rule.pattern());
             for usage in rule.usages() {
-                let value = usages.entry(usage.name()).or_insert(0);
-                *value += usage.delta();
+                let name = usage.name_for(plan);
+                merge_usage(ctx, &mut usages, name, usage.delta(), merge);
+            }
+            if let Some(metric) = rule.response_bytes_metric() {
+                if !response_metrics.contains(&metric) {
+                    response_metrics.push(metric);
+                }
             }
             if rule.is_last() {
                 debug!(ctx, "rule is last, stopping further evaluation");
@@ -107,15 +502,68 @@ pub fn authrep<'a>(
         }
     }
 
-    if usages.is_empty() {
-        anyhow::bail!(MatchError::NoUsageMatch);
+    (usages, response_metrics, matched)
+}
+
+// Adds a delta for a metric, combining it as configured if the metric was already hit.
+fn merge_usage<'a>(
+    ctx: &dyn IdentLogger,
+    usages: &mut HashMap<Cow<'a, str>, i64>,
+    name: &'a str,
+    delta: i64,
+    merge: UsageMerge,
+) {
+    match usages.get_mut(name) {
+        Some(value) => {
+            let merged = match merge {
+                UsageMerge::Sum => *value + delta,
+                UsageMerge::Max => core::cmp::max(*value, delta),
+                UsageMerge::Last => delta,
+            };
+            debug!(
+                ctx,
+                "metric {} hit again with delta {} over {}, {:?} results in {}",
+                name,
+                delta,
+                value,
+                merge,
+                merged
+            );
+            *value = merged;
+        }
+        None => {
+            usages.insert(Cow::from(name), delta);
+        }
     }
+}
 
-    Ok(AuthRep {
-        service,
-        apps,
-        usages,
-    })
+// Removes matrix parameters (ie. "/users;v=2/123" becomes "/users/123").
+pub fn strip_matrix_params(path: &str) -> Cow<str> {
+    if !path.contains(';') {
+        return Cow::from(path);
+    }
+
+    let stripped = path
+        .split('/')
+        .map(|segment| segment.split(';').next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join("/");
+    Cow::from(stripped)
+}
+
+// Replaces the path in the call's uri if the service overrides the backend endpoint.
+pub fn override_path<'u>(
+    service: &crate::threescale::Service,
+    kind: &Kind,
+    uri: &'u str,
+) -> Cow<'u, str> {
+    match service.backend_path(kind) {
+        Some(path) => {
+            let qs = uri.find('?').map_or("", |idx| &uri[idx..]);
+            Cow::from(format!("{}{}", path, qs))
+        }
+        None => Cow::from(uri),
+    }
 }
 
 pub fn build_call(ar: &AuthRep) -> Result<Request, anyhow::Error> {
@@ -137,14 +585,80 @@ pub fn build_call(ar: &AuthRep) -> Result<Request, anyhow::Error> {
     let usage = ar
         .usages()
         .iter()
-        .map(|(k, v)| (k, format!("{}", v)))
+        .map(|(k, v)| (k.to_string(), format!("{}", v)))
         .collect::<Vec<_>>();
-    let usage = Usage::new(usage.as_slice());
-    let txn = Transaction::new(app, None, Some(&usage), None);
-    let txns = vec![txn];
-    let extensions = extensions::List::new().no_body();
 
-    let service = ar.service();
+    build_request(ar.service(), &[(app, usage.as_slice())], ar.kind())
+}
+
+// Builds a report of usages for an application that has already been authorized.
+pub fn build_report(
+    service: &crate::threescale::Service,
+    app: &Application,
+    usage: &[(String, String)],
+) -> Result<Request, anyhow::Error> {
+    build_request(service, &[(app, usage)], Kind::Report)
+}
+
+// Builds a single report with the usages of several already authorized applications.
+pub fn build_batch_report(
+    service: &crate::threescale::Service,
+    reports: &[(Application, Vec<(String, String)>)],
+) -> Result<Request, anyhow::Error> {
+    let reports = reports
+        .iter()
+        .map(|(app, usage)| (app, usage.as_slice()))
+        .collect::<Vec<_>>();
+    build_request(service, reports.as_slice(), Kind::Report)
+}
+
+// Sends a report, which is not waited for, returning the token of the call.
+pub fn send_report<C: proxy_wasm::traits::Context>(
+    ctx: &C,
+    backend: &crate::threescale::Backend,
+    service: &crate::threescale::Service,
+    request: &Request,
+) -> Result<u32, anyhow::Error> {
+    let (uri, body) = request.uri_and_body();
+    let uri = override_path(service, &Kind::Report, uri.as_ref());
+    let headers = request
+        .headers
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect::<Vec<_>>();
+
+    service.backend_upstream(backend).call(
+        ctx,
+        uri.as_ref(),
+        request.method.as_str(),
+        headers,
+        None,
+        body.map(str::as_bytes),
+        None,
+        None,
+    )
+}
+
+fn build_request(
+    service: &crate::threescale::Service,
+    reports: &[(&Application, &[(String, String)])],
+    kind: Kind,
+) -> Result<Request, anyhow::Error> {
+    let usages = reports
+        .iter()
+        .map(|&(_, usage)| Usage::new(usage))
+        .collect::<Vec<_>>();
+    let txns = reports
+        .iter()
+        .zip(usages.iter())
+        .map(|(&(app, _), usage)| Transaction::new(app, None, Some(usage), None))
+        .collect::<Vec<_>>();
+    // the plan is only found in the body of authorizations
+    let extensions = match kind {
+        Kind::Report => extensions::List::new().no_body(),
+        _ if service.plan_header() => extensions::List::new(),
+        _ => extensions::List::new().no_body(),
+    };
 
     let service_token = if let Some(token) = service.token() {
         token
@@ -160,8 +674,218 @@ pub fn build_call(ar: &AuthRep) -> Result<Request, anyhow::Error> {
     let apicall = apicall
         .transactions(&txns)
         .extensions(&extensions)
-        .kind(Kind::AuthRep)
+        .kind(kind)
         .build()?;
 
     Ok(Request::from(&apicall))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::threescale::Usage;
+    use threescalers::http::mapping_rule::RestRule;
+
+    fn rule(method: Method, pattern: &str, name: &str, delta: i64) -> MappingRule {
+        MappingRule {
+            rule: RestRule::new(method, pattern).unwrap(),
+            usages: vec![Usage {
+                name: name.into(),
+                delta,
+                plans: None,
+            }],
+            last: false,
+            response_bytes_metric: None,
+        }
+    }
+
+    fn users_rule() -> MappingRule {
+        rule(Method::GET, "/users/{id}", "Hits", 1)
+    }
+
+    fn service() -> crate::threescale::Service {
+        serde_json::from_str(
+            r#"{
+              "id": "2555417834780",
+              "token": "service_token",
+              "credentials": {
+                "user_key": [{ "header": { "keys": ["x-api-key"] } }]
+              },
+              "mapping_rules": []
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn upstream() -> Upstream {
+        Upstream {
+            name: "outbound|443||su1.3scale.net".into(),
+            url: "https://su1.3scale.net".parse().unwrap(),
+            timeout: core::time::Duration::from_millis(5000),
+        }
+    }
+
+    fn oauth_authrep<'a>(service: &'a crate::threescale::Service, token: &str) -> AuthRep<'a> {
+        let mut usages = HashMap::new();
+        usages.insert(Cow::from("Hits"), 1);
+
+        AuthRep {
+            service,
+            apps: vec![Application::OAuthToken(token.into())],
+            source: None,
+            usages,
+            params: vec![],
+            response_metrics: vec![],
+            metered: true,
+            batched: false,
+            matched_rules: vec![],
+        }
+    }
+
+    #[test]
+    fn passes_query_params_through() {
+        let url =
+            url::Url::parse("https://api.example.com/users?plan=gold%20plus&tier=a%26b&plan=other")
+                .unwrap();
+
+        assert_eq!(query_param(&url, "plan"), Some("gold plus".to_string()));
+        assert_eq!(query_param(&url, "tier"), Some("a&b".to_string()));
+        assert_eq!(query_param(&url, "missing"), None);
+
+        let service = service();
+        let mut ar = oauth_authrep(&service, "a-token");
+        assert_eq!(ar.extra_query_string(), None);
+
+        ar.params = vec![
+            ("plan", "gold plus".to_string()),
+            ("app_tier", "a&b".to_string()),
+        ];
+        assert_eq!(
+            ar.extra_query_string().as_deref(),
+            Some("plan=gold+plus&app_tier=a%26b")
+        );
+    }
+
+    #[test]
+    fn passes_oauth_tokens_through() {
+        let service = service();
+        let upstream = upstream();
+        let tokens = ["a-token", "eyJhbGciOi.J9-_~/+=.aBc0123456789"];
+
+        for &token in tokens.iter() {
+            let ar = oauth_authrep(&service, token);
+            let headers = passthrough_headers(&ar, &upstream).unwrap();
+
+            assert_eq!(
+                headers,
+                vec![
+                    ("x-3scale-access-token", token.to_string()),
+                    (
+                        "x-3scale-cluster-name",
+                        "outbound|443||su1.3scale.net".to_string()
+                    ),
+                    (
+                        "x-3scale-upstream-url",
+                        "https://su1.3scale.net/".to_string()
+                    ),
+                    ("x-3scale-timeout", "5000".to_string()),
+                    ("x-3scale-service-id", "2555417834780".to_string()),
+                    ("x-3scale-service-token", "service_token".to_string()),
+                    ("x-3scale-usages", r#"{"Hits":1}"#.to_string()),
+                ]
+            );
+
+            let properties = passthrough_properties(&ar, &upstream).unwrap();
+            assert_eq!(properties[0], ("access-token", token.to_string()));
+            assert_eq!(properties[4], ("service-id", "2555417834780".to_string()));
+        }
+    }
+
+    #[test]
+    fn merges_extra_options() {
+        assert_eq!(
+            merged_options(&[("3scale-options", "no_body=1")], EXTRA_OPTIONS),
+            "no_body=1&rejection_reason_header=1&limit_headers=1"
+        );
+        assert_eq!(merged_options(&[], EXTRA_OPTIONS), EXTRA_OPTIONS);
+    }
+
+    #[test]
+    fn merges_deltas_of_repeated_metrics() {
+        let fixtures = [
+            (UsageMerge::Sum, 4),
+            (UsageMerge::Max, 3),
+            (UsageMerge::Last, 1),
+        ];
+
+        for &(merge, expected) in fixtures.iter() {
+            let mut usages = HashMap::new();
+            merge_usage(&"test", &mut usages, "Hits", 3, merge);
+            merge_usage(&"test", &mut usages, "Hits", 1, merge);
+            merge_usage(&"test", &mut usages, "other", 2, merge);
+
+            assert_eq!(
+                usages.get("Hits"),
+                Some(&expected),
+                "merging with {:?}",
+                merge
+            );
+            assert_eq!(usages.get("other"), Some(&2));
+        }
+    }
+
+    #[test]
+    fn strips_matrix_params_from_segments() {
+        assert_eq!(strip_matrix_params("/users;v=2/123"), "/users/123");
+        assert_eq!(strip_matrix_params("/users;v=2;x=y/123;a=b"), "/users/123");
+        assert_eq!(strip_matrix_params("/users/123"), "/users/123");
+        assert_eq!(strip_matrix_params("/;v=2"), "/");
+    }
+
+    #[test]
+    fn matrix_params_match_rule_once_stripped() {
+        let rule = users_rule();
+
+        assert!(!rule.is_match(&Method::GET, "/users;v=2/123"));
+        assert!(rule.is_match(&Method::GET, &strip_matrix_params("/users;v=2/123")));
+    }
+
+    #[test]
+    fn accumulates_deltas_of_all_matching_rules() {
+        let rules = vec![
+            users_rule(),
+            rule(Method::from("any"), "/users/{id}", "Hits", 2),
+            rule(Method::GET, "/users/{id}", "user_reads", 1),
+            rule(Method::POST, "/users/{id}", "user_writes", 1),
+        ];
+
+        let (usages, _, _) = match_rules(
+            &"test",
+            &rules,
+            &Method::GET,
+            "/users/42",
+            None,
+            UsageMerge::Sum,
+        );
+
+        assert_eq!(usages.len(), 2);
+        assert_eq!(usages.get("Hits"), Some(&3));
+        assert_eq!(usages.get("user_reads"), Some(&1));
+    }
+
+    #[test]
+    fn method_mismatch_contributes_no_usage() {
+        let rules = vec![users_rule()];
+
+        let (usages, _, _) = match_rules(
+            &"test",
+            &rules,
+            &Method::POST,
+            "/users/42",
+            None,
+            UsageMerge::Sum,
+        );
+
+        assert!(usages.is_empty());
+    }
+}
@@ -0,0 +1,378 @@
+// Queue of usage to be reported to 3scale in batches.
+//
+// The usage of authorized requests is aggregated per service and application
+// in the shared data of the host, so that all worker VMs add to the same
+// queue, and the whole queue is taken out when flushing it, sending a single
+// report per service. Like the cache index, the queue is updated with compare
+// and swap so that concurrent updates from other VMs are not lost. The usage
+// of each report in flight is kept until its response comes, and queued again
+// unless 3scale accepted it.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+use threescalers::application::Application;
+use url::form_urlencoded;
+
+use super::authrep;
+use super::metrics;
+use crate::configuration::api::v1::Configuration;
+use crate::log::IdentLogger;
+
+const QUEUE_KEY: &str = "3scale-batch-queue";
+const MAX_CAS_RETRIES: usize = 3;
+
+thread_local! {
+    // usage of the reports in flight, keyed on their call tokens
+    static IN_FLIGHT: RefCell<HashMap<u32, Queue>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Queue {
+    // requests whose usage has been queued
+    requests: usize,
+    // deltas per metric, keyed on the service id, credential kind and credential
+    usages: BTreeMap<(String, String, String), BTreeMap<String, i64>>,
+}
+
+fn credential(app: &Application) -> (&str, &str) {
+    match app {
+        Application::AppId(app_id, _) => ("app_id", app_id.as_ref()),
+        Application::UserKey(user_key) => ("user_key", user_key.as_ref()),
+        Application::OAuthToken(token) => ("oauth_token", token.as_ref()),
+    }
+}
+
+// reports don't need the application key, so only the identifier is kept
+fn application(kind: &str, id: &str) -> Option<Application> {
+    match kind {
+        "app_id" => Some(Application::AppId(id.into(), None)),
+        "user_key" => Some(Application::UserKey(id.into())),
+        "oauth_token" => Some(Application::OAuthToken(id.into())),
+        _ => None,
+    }
+}
+
+impl Queue {
+    fn add(&mut self, service_id: &str, app: &Application, usage: &[(String, i64)]) {
+        let (kind, id) = credential(app);
+        let deltas = self
+            .usages
+            .entry((service_id.to_string(), kind.to_string(), id.to_string()))
+            .or_default();
+        for (metric, delta) in usage {
+            *deltas.entry(metric.clone()).or_default() += delta;
+        }
+        self.requests += 1;
+    }
+
+    fn merge(&mut self, other: &Queue) {
+        for (key, deltas) in other.usages.iter() {
+            let pending = self.usages.entry(key.clone()).or_default();
+            for (metric, delta) in deltas {
+                *pending.entry(metric.clone()).or_default() += delta;
+            }
+        }
+        self.requests += other.requests;
+    }
+
+    // The usage of some of the services. Requests are not counted per
+    // service, so each application taken counts as one.
+    fn of_services(&self, service_ids: &[&str]) -> Self {
+        let usages = self
+            .usages
+            .iter()
+            .filter(|((service_id, _, _), _)| service_ids.contains(&service_id.as_str()))
+            .map(|(key, deltas)| (key.clone(), deltas.clone()))
+            .collect::<BTreeMap<_, _>>();
+
+        Self {
+            requests: usages.len(),
+            usages,
+        }
+    }
+
+    // Reports grouped by service id.
+    fn reports(&self) -> BTreeMap<&str, Vec<(Application, Vec<(String, String)>)>> {
+        let mut reports = BTreeMap::new();
+        for ((service_id, kind, id), deltas) in self.usages.iter() {
+            if let Some(app) = application(kind, id) {
+                let usage = deltas
+                    .iter()
+                    .map(|(metric, delta)| (metric.clone(), delta.to_string()))
+                    .collect();
+                reports
+                    .entry(service_id.as_str())
+                    .or_insert_with(Vec::new)
+                    .push((app, usage));
+            }
+        }
+        reports
+    }
+
+    // The first line is the number of requests, followed by a line per
+    // application with its form encoded identity and deltas separated by a tab.
+    fn encode(&self) -> String {
+        let mut data = format!("{}\n", self.requests);
+        for ((service_id, kind, id), deltas) in self.usages.iter() {
+            let app = form_urlencoded::Serializer::new(String::new())
+                .append_pair("service_id", service_id)
+                .append_pair("kind", kind)
+                .append_pair("id", id)
+                .finish();
+            let deltas = form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(
+                    deltas
+                        .iter()
+                        .map(|(metric, delta)| (metric, delta.to_string())),
+                )
+                .finish();
+            data.push_str(app.as_str());
+            data.push('\t');
+            data.push_str(deltas.as_str());
+            data.push('\n');
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        let data = String::from_utf8_lossy(data);
+        let mut lines = data.lines();
+        let requests = lines
+            .next()
+            .and_then(|requests| requests.parse().ok())
+            .unwrap_or_default();
+
+        let usages = lines
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, '\t');
+                let app =
+                    form_urlencoded::parse(parts.next()?.as_bytes()).collect::<BTreeMap<_, _>>();
+                let key = (
+                    app.get("service_id")?.to_string(),
+                    app.get("kind")?.to_string(),
+                    app.get("id")?.to_string(),
+                );
+                let deltas = form_urlencoded::parse(parts.next()?.as_bytes())
+                    .filter_map(|(metric, delta)| {
+                        delta.parse().ok().map(|delta| (metric.into_owned(), delta))
+                    })
+                    .collect();
+                Some((key, deltas))
+            })
+            .collect();
+
+        Self { requests, usages }
+    }
+}
+
+// Updates the queue in shared data, returning the result of the update.
+fn update<C: Context, T, F: Fn(&mut Queue) -> T>(ctx: &C, f: F) -> Result<T, Status> {
+    let mut retries = 0;
+    loop {
+        let (data, cas) = ctx.get_shared_data(QUEUE_KEY);
+        let mut queue = data
+            .map(|data| Queue::decode(data.as_slice()))
+            .unwrap_or_default();
+        let result = f(&mut queue);
+        match ctx.set_shared_data(QUEUE_KEY, Some(queue.encode().as_bytes()), cas) {
+            Ok(()) => return Ok(result),
+            Err(Status::CasMismatch) if retries < MAX_CAS_RETRIES => retries += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Queues the usage of an authorized request, returning the number of requests pending.
+pub fn add<C: Context>(
+    ctx: &C,
+    service_id: &str,
+    app: &Application,
+    usage: &[(String, i64)],
+) -> Result<usize, Status> {
+    update(ctx, |queue| {
+        queue.add(service_id, app, usage);
+        queue.requests
+    })
+}
+
+// Queues usage taken out of the queue again, ie. when failing to report it.
+fn requeue<C: Context + IdentLogger>(ctx: &C, requeued: &Queue) {
+    if let Err(e) = update(ctx, |pending| pending.merge(requeued)) {
+        error!(
+            ctx,
+            "dropping batched usage of {} requests: {:?}", requeued.requests, e
+        );
+    }
+}
+
+// reports are done with once 3scale accepted them
+fn accepted(status: Option<&str>) -> bool {
+    status.map_or(false, |status| status.starts_with('2'))
+}
+
+fn take_in_flight(token_id: u32) -> Option<Queue> {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&token_id))
+}
+
+// Takes out all the queued usage and reports it.
+pub fn flush<C: Context + IdentLogger>(ctx: &C, config: &Configuration) {
+    let queue = match update(ctx, std::mem::take) {
+        Ok(queue) => queue,
+        Err(e) => {
+            warn!(ctx, "failed to take batched usage: {:?}", e);
+            return;
+        }
+    };
+    if queue.requests == 0 {
+        return;
+    }
+
+    debug!(ctx, "flushing batched usage of {} requests", queue.requests);
+    // usage that could not be sent is queued again rather than lost, ie. while
+    // a configuration missing its service is being replaced
+    let mut failed = Vec::new();
+    for (service_id, reports) in queue.reports() {
+        let sent = config
+            .get_service(service_id)
+            .ok_or_else(|| anyhow::anyhow!("unknown service"))
+            .and_then(|service| {
+                let backend = config.get_service_backend(service)?;
+                authrep::build_batch_report(service, reports.as_slice())
+                    .and_then(|request| authrep::send_report(ctx, backend, service, &request))
+            });
+        match sent {
+            Ok(token) => {
+                metrics::record_reports_flushed(ctx, Some(service_id));
+                let reported = queue.of_services(&[service_id]);
+                IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(token, reported));
+            }
+            Err(e) => {
+                warn!(
+                    ctx,
+                    "failed to report batched usage of service {}, queueing it again: {:?}",
+                    service_id,
+                    e
+                );
+                failed.push(service_id);
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        requeue(ctx, &queue.of_services(failed.as_slice()));
+    }
+}
+
+// Handles the response of a call if it is a batch report, returning whether it
+// was. The usage of reports failing with any status other than a 2xx, or not
+// getting a response at all, is queued again.
+pub fn complete<C: Context + IdentLogger>(ctx: &C, token_id: u32, status: Option<&str>) -> bool {
+    let reported = match take_in_flight(token_id) {
+        Some(reported) => reported,
+        None => return false,
+    };
+    if accepted(status) {
+        debug!(ctx, "batch report {} got status {:?}", token_id, status);
+    } else {
+        warn!(
+            ctx,
+            "batch report {} failed with status {:?}, queueing its usage again", token_id, status
+        );
+        requeue(ctx, &reported);
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn aggregates_usage_per_application() {
+        let app = Application::UserKey("key".into());
+        let other = Application::AppId("app\tid".into(), Some("secret".into()));
+        let mut queue = Queue::default();
+        queue.add("1", &app, &[("Hits".into(), 1), ("reads".into(), 2)]);
+        queue.add("1", &app, &[("Hits".into(), 1)]);
+        queue.add("2", &other, &[("Hits".into(), 3)]);
+
+        assert_eq!(queue.requests, 3);
+        let reports = queue.reports();
+        assert_eq!(reports["1"].len(), 1);
+        assert_eq!(
+            reports["1"][0].1,
+            vec![
+                ("Hits".to_string(), "2".to_string()),
+                ("reads".to_string(), "2".to_string())
+            ]
+        );
+        assert_eq!(
+            reports["2"][0].1,
+            vec![("Hits".to_string(), "3".to_string())]
+        );
+    }
+
+    #[test]
+    fn requeues_usage_of_failed_services() {
+        let app = Application::UserKey("key".into());
+        let mut queue = Queue::default();
+        queue.add("1", &app, &[("Hits".into(), 1)]);
+        queue.add("2", &app, &[("Hits".into(), 2)]);
+        queue.add("2", &app, &[("Hits".into(), 1)]);
+        let requeued = queue.of_services(&["2"]);
+
+        // requests queued while the failed ones were being sent are kept
+        let mut pending = Queue::default();
+        pending.add("2", &app, &[("Hits".into(), 5), ("reads".into(), 1)]);
+        pending.merge(&requeued);
+
+        assert_eq!(pending.requests, 2);
+        let reports = pending.reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(
+            reports["2"][0].1,
+            vec![
+                ("Hits".to_string(), "8".to_string()),
+                ("reads".to_string(), "1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_usage_of_reports_in_flight() {
+        let app = Application::UserKey("key".into());
+        let mut queue = Queue::default();
+        queue.add("1", &app, &[("Hits".into(), 1)]);
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(7, queue.clone()));
+
+        assert_eq!(take_in_flight(8), None);
+        assert_eq!(take_in_flight(7), Some(queue));
+        assert_eq!(take_in_flight(7), None);
+
+        assert!(accepted(Some("202")));
+        assert!(!accepted(Some("503")));
+        assert!(!accepted(Some("403")));
+        // timed out
+        assert!(!accepted(None));
+    }
+
+    #[test]
+    fn queue_survives_encoding() {
+        let mut queue = Queue::default();
+        queue.add(
+            "1",
+            &Application::OAuthToken("a&b=c\n".into()),
+            &[("Hits".into(), 1)],
+        );
+        queue.add(
+            "2",
+            &Application::UserKey("key".into()),
+            &[("a b".into(), 4)],
+        );
+
+        assert_eq!(Queue::decode(queue.encode().as_bytes()), queue);
+        assert_eq!(Queue::decode(&[]), Queue::default());
+    }
+}
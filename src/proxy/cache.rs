@@ -0,0 +1,215 @@
+// Cache of authorization verdicts using the shared data of the host.
+//
+// Entries are keyed on a hash of the service, the application credentials
+// and the usages, and hold the verdict along with its expiration time.
+// Since shared data can't be enumerated, an index of the keys in least to
+// most recently stored order is kept under its own key to evict entries once
+// the maximum number is exceeded. The index is only updated when entries are
+// stored, so that hits don't rewrite it, and with compare and swap so that
+// concurrent updates from other VMs are not lost.
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+
+use threescalers::application::Application;
+
+use super::authrep::AuthRep;
+use super::limits;
+use crate::configuration::Cache;
+use crate::log::IdentLogger;
+use sha2::{Digest, Sha256};
+
+const PREFIX: &str = "3scale-auth-cache/";
+const INDEX_KEY: &str = "3scale-auth-cache-index";
+const MAX_CAS_RETRIES: usize = 3;
+
+// Only authorizations not reporting usage are cached, since serving those
+// that do from the cache would leave their usage unreported. Deferred and
+// batched usage is reported apart from the authorization, so it is never
+// lost on cache hits.
+pub fn is_cacheable(ar: &AuthRep) -> bool {
+    !ar.is_metered()
+        || ar.is_deferred()
+        || ar.is_batched()
+        || ar.usages().values().all(|&delta| delta == 0)
+}
+
+// Keys include the generation of the cache, so that bumping it in the
+// configuration purges all the entries, ie. after revoking a key.
+pub fn key(ar: &AuthRep, generation: u64) -> Option<String> {
+    let app = ar.apps().first()?;
+    let (kind, id, secret) = match app {
+        Application::AppId(app_id, app_key) => (
+            "app_id",
+            app_id.as_ref(),
+            app_key.as_ref().map_or("", |key| key.as_ref()),
+        ),
+        Application::UserKey(user_key) => ("user_key", user_key.as_ref(), ""),
+        Application::OAuthToken(token) => ("oauth_token", token.as_ref(), ""),
+    };
+
+    let mut usages = ar
+        .usages()
+        .iter()
+        .map(|(name, delta)| format!("{}={}", name, delta))
+        .collect::<Vec<_>>();
+    usages.sort_unstable();
+    let usages = usages.join("&");
+
+    Some(hash_key(
+        &[ar.service().id(), kind, id, secret, usages.as_str()],
+        generation,
+    ))
+}
+
+fn hash_key(parts: &[&str], generation: u64) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(generation.to_string().as_bytes());
+    for part in parts {
+        hasher.update(b"\n");
+        hasher.update(part.as_bytes());
+    }
+    let digest = hasher.finalize();
+    let hash = digest
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    format!("{}{}", PREFIX, hash)
+}
+
+// Key of the anonymous requests of a client, which holds no verdict but a
+// window of its local limits.
+pub fn client_key(service_id: &str, app_id: &str, client_ip: &str, generation: u64) -> String {
+    hash_key(&[service_id, "anonymous", app_id, client_ip], generation)
+}
+
+// An entry is the verdict byte followed by the expiration time in seconds.
+fn encode_entry(authorized: bool, expires_at: u64) -> [u8; 9] {
+    let mut entry = [0u8; 9];
+    entry[0] = authorized as u8;
+    entry[1..].copy_from_slice(&expires_at.to_be_bytes());
+    entry
+}
+
+fn decode_entry(entry: &[u8], now: u64) -> Option<bool> {
+    if entry.len() != 9 {
+        return None;
+    }
+    let mut expires_at = [0u8; 8];
+    expires_at.copy_from_slice(&entry[1..]);
+    if u64::from_be_bytes(expires_at) <= now {
+        return None;
+    }
+    Some(entry[0] != 0)
+}
+
+// Moves the key to the most recently stored end of the index, returning the
+// keys evicted to keep the index within max_entries.
+fn touch(index: &mut Vec<String>, key: &str, max_entries: usize) -> Vec<String> {
+    index.retain(|k| k != key);
+    index.push(key.to_string());
+    let excess = index.len().saturating_sub(max_entries);
+    index.drain(..excess).collect()
+}
+
+fn parse_index(data: Option<Vec<u8>>) -> Vec<String> {
+    data.map(|data| {
+        String::from_utf8_lossy(data.as_slice())
+            .lines()
+            .filter(|k| !k.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn update_index<C: Context>(ctx: &C, key: &str, max_entries: usize) -> Result<(), Status> {
+    let mut retries = 0;
+    loop {
+        let (data, cas) = ctx.get_shared_data(INDEX_KEY);
+        let mut index = parse_index(data);
+        let evicted = touch(&mut index, key, max_entries);
+        match ctx.set_shared_data(INDEX_KEY, Some(index.join("\n").as_bytes()), cas) {
+            Ok(()) => {
+                for evicted in evicted {
+                    // shared data can't be removed, but empty entries are misses
+                    let _ = ctx.set_shared_data(evicted.as_str(), Some(&[] as &[u8]), None);
+                    let _ = ctx.set_shared_data(
+                        limits::key(evicted.as_str()).as_str(),
+                        Some(&[] as &[u8]),
+                        None,
+                    );
+                }
+                return Ok(());
+            }
+            Err(Status::CasMismatch) if retries < MAX_CAS_RETRIES => retries += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub fn get<C: Context>(ctx: &C, key: &str, now: u64) -> Option<bool> {
+    let (data, _) = ctx.get_shared_data(key);
+    decode_entry(data?.as_slice(), now)
+}
+
+// Counts the key as stored, so that it is evicted along with its limits, as
+// there can be as many keys as clients.
+pub fn touch_key<C: Context + IdentLogger>(ctx: &C, cache: &Cache, key: &str) {
+    if let Err(e) = update_index(ctx, key, cache.max_entries()) {
+        debug!(ctx, "failed to update authorization cache index: {:?}", e);
+    }
+}
+
+pub fn set<C: Context + IdentLogger>(
+    ctx: &C,
+    cache: &Cache,
+    key: &str,
+    authorized: bool,
+    now: u64,
+) {
+    let entry = encode_entry(authorized, now.saturating_add(cache.ttl()));
+    if let Err(e) = ctx.set_shared_data(key, Some(&entry[..]), None) {
+        warn!(ctx, "failed to store authorization in cache: {:?}", e);
+        return;
+    }
+    if let Err(e) = update_index(ctx, key, cache.max_entries()) {
+        warn!(ctx, "failed to update authorization cache index: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entries_expire() {
+        let entry = encode_entry(true, 100);
+        assert_eq!(decode_entry(&entry, 99), Some(true));
+        assert_eq!(decode_entry(&entry, 100), None);
+        assert_eq!(decode_entry(&encode_entry(false, 100), 99), Some(false));
+        assert_eq!(decode_entry(&[], 0), None);
+    }
+
+    #[test]
+    fn generations_purge_keys() {
+        let parts = ["1", "user_key", "key", "", "Hits=1"];
+        assert_eq!(hash_key(&parts, 0), hash_key(&parts, 0));
+        assert_ne!(hash_key(&parts, 0), hash_key(&parts, 1));
+        assert_ne!(
+            hash_key(&parts, 0),
+            hash_key(&["1", "user_key", "other", "", "Hits=1"], 0)
+        );
+    }
+
+    #[test]
+    fn index_evicts_least_recently_stored() {
+        let mut index = vec![];
+        assert!(touch(&mut index, "a", 2).is_empty());
+        assert!(touch(&mut index, "b", 2).is_empty());
+        // storing "a" again makes "b" the least recently stored
+        assert!(touch(&mut index, "a", 2).is_empty());
+        assert_eq!(touch(&mut index, "c", 2), vec!["b".to_string()]);
+        assert_eq!(index, vec!["a".to_string(), "c".to_string()]);
+    }
+}
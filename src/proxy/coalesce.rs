@@ -0,0 +1,485 @@
+// Coalescing of identical authorizations in flight.
+//
+// A request about to call 3scale with the same authorization as one already
+// waiting for its response registers itself as a waiter of that call instead,
+// and is resumed or denied along with the request that sent it.
+//
+// A context can only act on the streams of its own worker VM, so calls in
+// flight are also marked in the shared data of the host, with the queues of
+// the other VMs waiting for them. The request that sent the call sends its
+// verdict to those queues, and the root context of each VM releases its own
+// waiters. A marker outliving the timeout of its call is taken over by the
+// next request, and the waiters of a VM are released as failed calls once it
+// expires, so a VM that is gone cannot leave requests waiting forever.
+//
+// The request that sent the call can act on the streams of its waiters but not
+// on their contexts, so what each waiter is to change in its own state is left
+// for it to settle once it runs again.
+use core::fmt::Write as _;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use proxy_wasm::hostcalls;
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+
+use super::metrics::Outcome;
+use crate::log::IdentLogger;
+
+const MARKER_PREFIX: &str = "3scale-coalesce/";
+const MAX_CAS_RETRIES: usize = 3;
+
+thread_local! {
+    // ids of the contexts waiting for each call in flight
+    static CALLS: RefCell<HashMap<String, Vec<u32>>> = RefCell::new(HashMap::new());
+    // what each waiter released is to change in its own state
+    static SETTLEMENTS: RefCell<HashMap<u32, Settlement>> = RefCell::new(HashMap::new());
+    // deadlines and services of the calls sent from other VMs that waiters of
+    // this one wait for
+    static REMOTE: RefCell<HashMap<String, (u64, String)>> = RefCell::new(HashMap::new());
+    // queue the verdicts of calls sent from other VMs are received on
+    static QUEUE: Cell<Option<u32>> = Cell::new(None);
+}
+
+// How a context is to get the verdict of its authorization.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Claim {
+    // waiting for a call sent from this VM
+    Local,
+    // waiting for a call sent from another VM
+    Remote,
+    // the context is to send the call itself
+    Send,
+}
+
+// A call in flight as marked in shared data.
+#[derive(Debug, Clone, PartialEq)]
+struct Marker {
+    deadline: u64,
+    // queue of the VM and id of the context that sent the call
+    queue: u32,
+    context_id: u32,
+    // queues of the other VMs waiting for it
+    waiting: Vec<u32>,
+}
+
+impl Marker {
+    fn encode(&self) -> String {
+        let mut encoded = format!("{} {} {}", self.deadline, self.queue, self.context_id);
+        for queue in self.waiting.iter() {
+            write!(&mut encoded, "\n{}", queue).unwrap();
+        }
+        encoded
+    }
+
+    // markers of calls already done are left empty
+    fn decode(data: &[u8]) -> Option<Self> {
+        let data = core::str::from_utf8(data).ok()?;
+        let mut lines = data.lines();
+        let mut owner = lines.next()?.split(' ');
+        let deadline = owner.next()?.parse().ok()?;
+        let queue = owner.next()?.parse().ok()?;
+        let context_id = owner.next()?.parse().ok()?;
+        let waiting = lines.filter_map(|line| line.parse().ok()).collect();
+
+        Some(Self {
+            deadline,
+            queue,
+            context_id,
+            waiting,
+        })
+    }
+}
+
+// The verdict of a call sent to the VMs waiting for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Release {
+    pub key: String,
+    pub service_id: Option<String>,
+    pub outcome: Outcome,
+    // why 3scale rejected the request
+    pub reason: Option<String>,
+}
+
+impl Release {
+    fn encode(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}",
+            self.key,
+            self.service_id.as_deref().unwrap_or_default(),
+            self.outcome.as_str(),
+            self.reason.as_deref().unwrap_or_default()
+        )
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let data = core::str::from_utf8(data).ok()?;
+        let mut fields = data.splitn(4, '\n');
+        let key = fields.next()?.to_string();
+        let service_id = Some(fields.next()?)
+            .filter(|id| !id.is_empty())
+            .map(str::to_string);
+        let outcome = match fields.next()? {
+            "authorized" => Outcome::Authorized,
+            "denied" => Outcome::Denied,
+            "limits_exceeded" => Outcome::LimitsExceeded,
+            "backend_error" => Outcome::BackendError,
+            _ => return None,
+        };
+        let reason = fields
+            .next()
+            .filter(|reason| !reason.is_empty())
+            .map(str::to_string);
+
+        Some(Self {
+            key,
+            service_id,
+            outcome,
+            reason,
+        })
+    }
+}
+
+fn marker_key(key: &str) -> String {
+    format!("{}{}", MARKER_PREFIX, key)
+}
+
+// Changes to the state of a context from the verdict of its call.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settlement {
+    pub authorized: bool,
+    // the backend could not be reached, yet the request was allowed
+    pub unvalidated: bool,
+    pub plan: Option<String>,
+    pub rate_limits: Vec<(&'static str, String)>,
+    // why the request was denied, if it was
+    pub denial: Option<String>,
+}
+
+// Registers the queue of this VM, named after its id, unless already registered.
+pub fn register_queue<C: IdentLogger>(ctx: &C, vm_id: u32) {
+    if QUEUE.with(Cell::get).is_some() {
+        return;
+    }
+    match hostcalls::register_shared_queue(marker_key(vm_id.to_string().as_str()).as_str()) {
+        Ok(queue) => QUEUE.with(|q| q.set(Some(queue))),
+        Err(e) => warn!(
+            ctx,
+            "failed to register queue, calls are only coalesced within this VM: {:?}", e
+        ),
+    }
+}
+
+// Registers the context as a waiter of the call in flight with the key,
+// returning false if there is none, in which case the context is to send it.
+fn join(key: &str, context_id: u32) -> bool {
+    CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        match calls.get_mut(key) {
+            Some(waiters) => {
+                waiters.push(context_id);
+                true
+            }
+            None => {
+                calls.insert(key.to_string(), Vec::new());
+                false
+            }
+        }
+    })
+}
+
+// Marks the call as sent by the context unless another VM has a call in
+// flight for it, in which case the context is registered as a waiter.
+fn mark<C: Context + IdentLogger>(
+    ctx: &C,
+    key: &str,
+    service_id: &str,
+    context_id: u32,
+    now: u64,
+    deadline: u64,
+) -> Result<Claim, Status> {
+    let queue = match QUEUE.with(Cell::get) {
+        Some(queue) => queue,
+        None => return Ok(Claim::Send),
+    };
+    let marker_key = marker_key(key);
+    let mut retries = 0;
+    loop {
+        let (data, cas) = ctx.get_shared_data(marker_key.as_str());
+        // markers left by this VM were not cleared by a context that is gone
+        let current = data
+            .as_deref()
+            .and_then(Marker::decode)
+            .filter(|marker| marker.deadline > now && marker.queue != queue);
+        let (marker, claim) = match current {
+            Some(mut marker) => {
+                if !marker.waiting.contains(&queue) {
+                    marker.waiting.push(queue);
+                }
+                (marker, Claim::Remote)
+            }
+            None => (
+                Marker {
+                    deadline,
+                    queue,
+                    context_id,
+                    waiting: Vec::new(),
+                },
+                Claim::Send,
+            ),
+        };
+        match ctx.set_shared_data(marker_key.as_str(), Some(marker.encode().as_bytes()), cas) {
+            Ok(()) => {
+                if claim == Claim::Remote {
+                    REMOTE.with(|remote| {
+                        remote
+                            .borrow_mut()
+                            .insert(key.to_string(), (marker.deadline, service_id.to_string()))
+                    });
+                }
+                return Ok(claim);
+            }
+            Err(Status::CasMismatch) if retries < MAX_CAS_RETRIES => retries += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Finds out whether the context is to send the call with the key or wait for
+// one in flight, either in this VM or in another one.
+pub fn claim<C: Context + IdentLogger>(
+    ctx: &C,
+    key: &str,
+    service_id: &str,
+    context_id: u32,
+    now: u64,
+    deadline: u64,
+) -> Claim {
+    let stale = REMOTE.with(|remote| {
+        let mut remote = remote.borrow_mut();
+        match remote.get(key) {
+            Some(&(expires, _)) if expires <= now => remote.remove(key).is_some(),
+            _ => false,
+        }
+    });
+    if stale {
+        // the call is sent again, and its waiters released along with this context
+        debug!(ctx, "taking over a coalesced call sent from another VM");
+    } else if join(key, context_id) {
+        return Claim::Local;
+    }
+
+    let claim = mark(ctx, key, service_id, context_id, now, deadline).unwrap_or_else(|e| {
+        warn!(ctx, "failed to mark coalesced call: {:?}", e);
+        Claim::Send
+    });
+    if claim == Claim::Remote {
+        CALLS.with(|calls| {
+            if let Some(waiters) = calls.borrow_mut().get_mut(key) {
+                waiters.push(context_id);
+            }
+        });
+    }
+    claim
+}
+
+// Clears the marker of the call sent by the context and sends its verdict to
+// the VMs waiting for it, unless another context took it over.
+pub fn notify<C: Context + IdentLogger>(ctx: &C, context_id: u32, release: &Release) {
+    let queue = match QUEUE.with(Cell::get) {
+        Some(queue) => queue,
+        None => return,
+    };
+    let marker_key = marker_key(release.key.as_str());
+    let mut retries = 0;
+    let waiting = loop {
+        let (data, cas) = ctx.get_shared_data(marker_key.as_str());
+        let marker = match data.as_deref().and_then(Marker::decode) {
+            Some(marker) if marker.queue == queue && marker.context_id == context_id => marker,
+            _ => return,
+        };
+        match ctx.set_shared_data(marker_key.as_str(), Some(&[] as &[u8]), cas) {
+            Ok(()) => break marker.waiting,
+            Err(Status::CasMismatch) if retries < MAX_CAS_RETRIES => retries += 1,
+            Err(e) => {
+                warn!(ctx, "failed to clear marker of coalesced call: {:?}", e);
+                break marker.waiting;
+            }
+        }
+    };
+
+    let message = release.encode();
+    for queue in waiting {
+        if let Err(e) = hostcalls::enqueue_shared_queue(queue, Some(message.as_bytes())) {
+            warn!(
+                ctx,
+                "failed to send verdict of coalesced call to queue {}: {:?}", queue, e
+            );
+        }
+    }
+}
+
+// Takes the verdicts received on the queue of this VM.
+pub fn receive<C: IdentLogger>(ctx: &C, queue: u32) -> Vec<Release> {
+    let mut releases = Vec::new();
+    loop {
+        match hostcalls::dequeue_shared_queue(queue) {
+            Ok(Some(message)) => match Release::decode(message.as_slice()) {
+                Some(release) => {
+                    REMOTE.with(|remote| remote.borrow_mut().remove(release.key.as_str()));
+                    releases.push(release);
+                }
+                None => warn!(ctx, "ignoring malformed verdict of coalesced call"),
+            },
+            Ok(None) => break,
+            Err(e) => {
+                warn!(
+                    ctx,
+                    "failed to receive verdicts of coalesced calls: {:?}", e
+                );
+                break;
+            }
+        }
+    }
+    releases
+}
+
+// Takes out the calls sent from other VMs that are past their deadline, to
+// release their waiters as if the calls had failed.
+pub fn expired(now: u64) -> Vec<Release> {
+    REMOTE.with(|remote| {
+        let mut remote = remote.borrow_mut();
+        let expired: Vec<String> = remote
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|key| {
+                let (_, service_id) = remote.remove(key.as_str())?;
+                Some(Release {
+                    key,
+                    service_id: Some(service_id),
+                    outcome: Outcome::BackendError,
+                    reason: None,
+                })
+            })
+            .collect()
+    })
+}
+
+// Removes the call once it is done, returning its waiters.
+pub fn complete(key: &str) -> Vec<u32> {
+    CALLS.with(|calls| calls.borrow_mut().remove(key).unwrap_or_default())
+}
+
+// Leaves the settlement for the waiter to take.
+pub fn settle(context_id: u32, settlement: Settlement) {
+    SETTLEMENTS.with(|settlements| settlements.borrow_mut().insert(context_id, settlement));
+}
+
+// Takes the settlement left for the context, if any.
+pub fn settlement(context_id: u32) -> Option<Settlement> {
+    SETTLEMENTS.with(|settlements| settlements.borrow_mut().remove(&context_id))
+}
+
+// Runs f on the stream of each waiter still alive, switching back to the
+// context of the caller afterwards.
+pub fn for_each_waiter<C: IdentLogger, F: FnMut(u32)>(
+    ctx: &C,
+    waiters: &[u32],
+    context_id: u32,
+    mut f: F,
+) {
+    for &waiter in waiters {
+        match hostcalls::set_effective_context(waiter) {
+            Ok(()) => f(waiter),
+            Err(e) => debug!(ctx, "coalesced request {} is gone: {:?}", waiter, e),
+        }
+    }
+    if let Err(e) = hostcalls::set_effective_context(context_id) {
+        error!(
+            ctx,
+            "failed to switch back to context {}: {:?}", context_id, e
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coalesces_calls_with_the_same_key() {
+        assert!(!join("a", 1));
+        assert!(join("a", 2));
+        assert!(join("a", 3));
+        assert!(!join("b", 4));
+
+        assert_eq!(complete("a"), vec![2, 3]);
+        assert!(complete("a").is_empty());
+        // a new call is sent once the previous one is done
+        assert!(!join("a", 5));
+        assert!(complete("b").is_empty());
+    }
+
+    #[test]
+    fn encodes_markers_of_calls_in_flight() {
+        let marker = Marker {
+            deadline: 1_600_000_005,
+            queue: 7,
+            context_id: 42,
+            waiting: vec![3, 9],
+        };
+        let encoded = marker.encode();
+
+        assert_eq!(encoded, "1600000005 7 42\n3\n9");
+        assert_eq!(Marker::decode(encoded.as_bytes()), Some(marker));
+        // cleared once the call is done
+        assert_eq!(Marker::decode(b""), None);
+        assert_eq!(Marker::decode(b"1600000005 7"), None);
+    }
+
+    #[test]
+    fn encodes_releases() {
+        let denied = Release {
+            key: "k".to_string(),
+            service_id: Some("2555417777820".to_string()),
+            outcome: Outcome::Denied,
+            reason: Some("user_key_invalid".to_string()),
+        };
+        let failed = Release {
+            key: "k".to_string(),
+            service_id: None,
+            outcome: Outcome::BackendError,
+            reason: None,
+        };
+
+        assert_eq!(Release::decode(denied.encode().as_bytes()), Some(denied));
+        assert_eq!(Release::decode(failed.encode().as_bytes()), Some(failed));
+        assert_eq!(Release::decode(b"k\n\ncache_hit\n"), None);
+    }
+
+    #[test]
+    fn takes_out_expired_remote_calls() {
+        REMOTE.with(|remote| {
+            let mut remote = remote.borrow_mut();
+            remote.insert("d".to_string(), (10, "1".to_string()));
+            remote.insert("e".to_string(), (20, "2".to_string()));
+        });
+
+        assert!(expired(9).is_empty());
+        assert_eq!(
+            expired(10),
+            vec![Release {
+                key: "d".to_string(),
+                service_id: Some("1".to_string()),
+                outcome: Outcome::BackendError,
+                reason: None,
+            }]
+        );
+        assert!(expired(10).is_empty());
+        assert_eq!(expired(30).len(), 1);
+    }
+}
@@ -0,0 +1,68 @@
+// Record of how a request was handled, logged as a single JSON line once the
+// request is done so that its authorization can be followed at a glance.
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use threescalers::application::Application;
+
+use super::authrep::AuthRep;
+
+#[derive(Debug, Default, Serialize)]
+pub struct Decision {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_id: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mapping_rules: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credential_kind: Option<&'static str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    usages: BTreeMap<String, i64>,
+    // "hit" or "miss"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend_latency_ms: Option<u64>,
+    decision: &'static str,
+    // why the request was denied, if it was
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl Decision {
+    pub fn authrep(&mut self, ar: &AuthRep) {
+        self.service_id = Some(ar.service().id().to_string());
+        self.mapping_rules = ar.matched_rules().to_vec();
+        self.credential_kind = ar.apps().first().map(|app| match app {
+            Application::AppId(..) => "app_id",
+            Application::UserKey(_) => "user_key",
+            Application::OAuthToken(_) => "oauth_token",
+        });
+        self.usages = ar
+            .usages()
+            .iter()
+            .map(|(metric, delta)| (metric.to_string(), *delta))
+            .collect();
+    }
+
+    pub fn cache(&mut self, hit: bool) {
+        self.cache = Some(if hit { "hit" } else { "miss" });
+    }
+
+    pub fn backend_latency(&mut self, millis: u64) {
+        self.backend_latency_ms = Some(millis);
+    }
+
+    pub fn deny(&mut self, reason: String) {
+        self.reason = Some(reason);
+    }
+
+    // Requests not denied were let through.
+    pub fn to_json(&mut self) -> Result<String, serde_json::Error> {
+        self.decision = if self.reason.is_some() {
+            "denied"
+        } else {
+            "allowed"
+        };
+        serde_json::to_string(self)
+    }
+}
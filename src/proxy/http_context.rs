@@ -1,18 +1,118 @@
+use core::convert::TryFrom;
 use proxy_wasm::traits::{Context, HttpContext};
-use proxy_wasm::types::FilterHeadersStatus;
-use threescalers::application::Application;
+use proxy_wasm::types::{FilterDataStatus, FilterHeadersStatus, FilterTrailersStatus};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-use crate::configuration::Configuration;
+use crate::configuration::{Cache, Configuration, OnBackendError, Response};
 use crate::log::IdentLogger;
+use crate::threescale::{response, response_rule, NoMatchBehavior, Sanitize, Service};
+use crate::upstream::Upstream;
+use threescalers::api_call::Kind;
+use threescalers::application::Application;
 
 use super::authrep;
+use super::batch;
+use super::cache;
+use super::coalesce::{self, Claim, Settlement};
+use super::decision::Decision;
+use super::limits;
+use super::metrics::{self, Outcome};
 use super::request_headers::RequestHeaders;
+use super::signature::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use super::verdict::{self, DenyReason, Verdict};
+
+// Verdicts from the primary and the shadow backends, compared once both are known.
+#[derive(Debug, Default)]
+pub struct ShadowState {
+    token: Option<u32>,
+    primary: Option<bool>,
+    shadow: Option<bool>,
+}
+
+impl ShadowState {
+    fn is_shadow_call(&self, token_id: u32) -> bool {
+        self.token == Some(token_id)
+    }
+
+    fn mismatch(&self) -> Option<(bool, bool)> {
+        match (self.primary, self.shadow) {
+            (Some(primary), Some(shadow)) if primary != shadow => Some((primary, shadow)),
+            _ => None,
+        }
+    }
+}
+
+// A call to the backend, kept to be dispatched again on transient failures.
+#[derive(Debug)]
+pub struct BackendCall {
+    service_id: String,
+    path: String,
+    method: String,
+    headers: Vec<(String, String)>,
+    extra_qs: Option<String>,
+    body: Option<String>,
+    // authorizations can be sent again, unlike calls that also report usage
+    retriable: bool,
+    // counting from zero
+    attempt: u32,
+}
+
+impl BackendCall {
+    fn dispatch<C: Context>(
+        &self,
+        ctx: &C,
+        upstream: &Upstream,
+        timeout_ms: Option<u64>,
+    ) -> Result<u32, anyhow::Error> {
+        upstream.call(
+            ctx,
+            self.path.as_str(),
+            self.method.as_str(),
+            self.headers
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str()))
+                .collect(),
+            self.extra_qs.as_deref(),
+            self.body.as_deref().map(str::as_bytes),
+            None,
+            timeout_ms,
+        )
+    }
+}
 
 pub struct HttpAuthThreescale {
-    pub configuration: Configuration,
+    pub configuration: Rc<Configuration>,
     pub context_id: u32,
     pub id: u32,
     pub log_id: String,
+    pub shadow: ShadowState,
+    pub auth: Option<authrep::AuthState>,
+    // response body bytes seen so far
+    pub response_bytes: u64,
+    // tokens of report calls, whose responses are only logged
+    pub reports: Vec<u32>,
+    // plan returned by 3scale, to be told to clients
+    pub plan: Option<String>,
+    // key to cache the verdict of the backend under
+    pub cache_key: Option<String>,
+    // the call to the backend, to be retried if its service allows it
+    pub backend_call: Option<BackendCall>,
+    // key of the call to the backend that identical requests wait for
+    pub coalesce_key: Option<String>,
+    // how the request was handled, to be logged once it is done
+    pub decision: RefCell<Decision>,
+    // when the call to the backend was dispatched
+    pub backend_call_started: Option<std::time::SystemTime>,
+    // limits of the application returned by 3scale, to be told to clients
+    pub rate_limits: Vec<(&'static str, String)>,
+    // set while buffering the request body to look up credentials in it
+    pub body_limit: Option<usize>,
+    pub request_body: Option<Vec<u8>>,
+    // set while the request body is held back waiting for the verdict of its call
+    pub awaiting_verdict: bool,
+    // headers to remove from the response, as told by the service
+    pub sanitize: Option<Sanitize>,
 }
 
 impl IdentLogger for HttpAuthThreescale {
@@ -43,19 +143,337 @@ impl HttpContext for HttpAuthThreescale {
         //    Ok(backend) => backend,
         //};
 
-        let backend = self.configuration().get_backend().ok();
+        let rh = self.sanitize_request(RequestHeaders::new(self));
+        if let Some(status) = self.bypass(&rh) {
+            return status;
+        }
+
+        self.body_limit = self.body_limit(&rh);
+        if self.body_limit.is_some() {
+            debug!(
+                self,
+                "on_http_request_headers: waiting for the request body to look up credentials"
+            );
+            return FilterHeadersStatus::StopIteration;
+        }
+
+        let status = self.authorize(&rh);
+        // body data continuing would let the request through before its verdict
+        self.awaiting_verdict = matches!(status, FilterHeadersStatus::StopIteration);
+        status
+    }
 
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> FilterDataStatus {
+        self.take_settlement();
+        // the rest of the body is held back until the request is authorized
+        if self.awaiting_verdict {
+            return FilterDataStatus::StopIterationAndBuffer;
+        }
+        let limit = match self.body_limit {
+            Some(limit) => limit,
+            None => return FilterDataStatus::Continue,
+        };
+        // bodies over the limit are looked up in their first bytes only
+        if !end_of_stream && body_size < limit {
+            return FilterDataStatus::StopIterationAndBuffer;
+        }
+
+        self.body_limit = None;
+        self.request_body = self.get_http_request_body(0, core::cmp::min(body_size, limit));
         let rh = RequestHeaders::new(self);
+        match self.authorize(&rh) {
+            FilterHeadersStatus::Continue => FilterDataStatus::Continue,
+            _ => {
+                self.awaiting_verdict = true;
+                FilterDataStatus::StopIterationAndBuffer
+            }
+        }
+    }
+
+    fn on_http_response_headers(&mut self, _: usize) -> FilterHeadersStatus {
+        self.take_settlement();
+        if let Some(sanitize) = self.sanitize.as_ref() {
+            for (name, _) in self.get_http_response_headers() {
+                if sanitize.strips_response_header(name.as_str()) {
+                    self.set_http_response_header(name.as_str(), None);
+                }
+            }
+        }
+        if !self
+            .sanitize
+            .as_ref()
+            .map_or(false, Sanitize::response_headers)
+        {
+            self.set_http_response_header("Powered-By", Some("3scale"));
+        }
+        if let Some(source) = self.auth.as_ref().and_then(|auth| auth.source()) {
+            self.set_http_response_header("X-3scale-Auth-Source", Some(source));
+        }
+        if let Some(plan) = self.plan.as_deref() {
+            self.set_http_response_header("X-3scale-Plan", Some(plan));
+        }
+        for (name, value) in self.rate_limits.iter() {
+            self.set_http_response_header(name, Some(value.as_str()));
+        }
+        self.report_deferred_usage(false);
+        self.report_response_rules_usage();
+        FilterHeadersStatus::Continue
+    }
+
+    fn on_http_response_trailers(&mut self, _: usize) -> FilterTrailersStatus {
+        self.report_deferred_usage(true);
+        FilterTrailersStatus::Continue
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> FilterDataStatus {
+        // streamed bodies are seen in chunks, so the size is only known at the end
+        self.response_bytes += body_size as u64;
+        if end_of_stream {
+            self.report_response_bytes();
+        }
+        FilterDataStatus::Continue
+    }
+
+    fn on_log(&mut self) {
+        self.take_settlement();
+        if !self.configuration().decision_log() {
+            return;
+        }
+        match self.decision.borrow_mut().to_json() {
+            Ok(record) => info!(self, "{}", record),
+            Err(e) => warn!(self, "failed to encode decision log: {:?}", e),
+        }
+    }
+}
+
+impl Context for HttpAuthThreescale {
+    fn on_http_call_response(&mut self, token_id: u32, _: usize, body_size: usize, _: usize) {
+        info!(
+            self,
+            "http_ctx: on_http_call_response: token id is {}", token_id
+        );
+        let headers = self.get_http_call_response_headers();
+        let status = headers
+            .iter()
+            .find(|(key, _)| key.as_str() == ":status")
+            .map(|(_, value)| value.clone());
+
+        if batch::complete(self, token_id, status.as_deref()) {
+            return;
+        }
+        if let Some(idx) = self.reports.iter().position(|&token| token == token_id) {
+            self.reports.swap_remove(idx);
+            debug!(
+                self,
+                "on_http_call_response: report {} got status {:?}", token_id, status
+            );
+            return;
+        }
+
+        let authorized = status.as_deref() == Some("200");
+        // no status means the call failed or timed out
+        let unreachable = status
+            .as_deref()
+            .map_or(true, |status| status.starts_with('5'));
+
+        if self.shadow.is_shadow_call(token_id) {
+            debug!(
+                self,
+                "on_http_call_response: shadow backend authorized: {}", authorized
+            );
+            self.shadow.shadow = Some(authorized);
+            self.compare_shadow_verdicts();
+            return;
+        }
+
+        if unreachable && self.retry_backend_call() {
+            return;
+        }
+
+        self.shadow.primary = Some(authorized);
+        self.compare_shadow_verdicts();
+
+        let service_id = self.auth.as_ref().map(|auth| auth.service_id().to_string());
+        if let Some(started) = self.backend_call_started.take() {
+            let latency = self
+                .get_current_time()
+                .duration_since(started)
+                .unwrap_or_default();
+            metrics::record_backend_latency(self, latency, service_id.as_deref());
+            let millis = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+            self.decision.borrow_mut().backend_latency(millis);
+        }
+
+        let outcome = if authorized {
+            Outcome::Authorized
+        } else if unreachable {
+            Outcome::BackendError
+        } else if self.limits_exceeded(headers.as_slice(), status.as_deref(), body_size) {
+            Outcome::LimitsExceeded
+        } else {
+            Outcome::Denied
+        };
+
+        // exceeded limits reset sooner than verdicts expire, and are answered
+        // with their rate limiting headers, so only other verdicts are cached
+        let cacheable = outcome == Outcome::Authorized || outcome == Outcome::Denied;
+        if let Some(key) = self.cache_key.as_deref().filter(|_| cacheable) {
+            if let Some(config) = self.configuration().cache() {
+                cache::set(self, config, key, authorized, self.now());
+                if authorized && config.local_limits() {
+                    limits::set(self, key, headers.as_slice(), self.now());
+                }
+            }
+        }
+        metrics::record(self, outcome, service_id.as_deref());
+
+        let configuration = Rc::clone(&self.configuration);
+        let service = service_id
+            .as_deref()
+            .and_then(|id| configuration.get().get_service(id));
+        let reason = Some(())
+            .filter(|_| outcome == Outcome::Denied)
+            .and_then(|_| self.rejection_reason(headers.as_slice(), body_size));
+        // the body of the response can only be read while handling it
+        let plan = Some(())
+            .filter(|_| outcome == Outcome::Authorized)
+            .and_then(|_| self.plan_from_response(body_size));
+        let verdict = Verdict {
+            outcome,
+            headers: headers.as_slice(),
+            status: status.as_deref(),
+            plan: plan.as_deref(),
+            token_id,
+            reason: reason.as_deref(),
+        };
+
+        if let Some(key) = self.coalesce_key.take() {
+            self.release_waiters(key.as_str(), &verdict, service);
+        }
+        if outcome == Outcome::Authorized {
+            self.sign_request();
+        }
+        let settlement = verdict::respond(self, configuration.get(), &verdict, service);
+        self.settle(settlement);
+    }
+
+    fn on_done(&mut self) -> bool {
+        // requests waiting on a call whose response will never come fail like it
+        if let Some(key) = self.coalesce_key.take() {
+            let configuration = Rc::clone(&self.configuration);
+            let service = self
+                .auth
+                .as_ref()
+                .and_then(|auth| configuration.get().get_service(auth.service_id()));
+            let verdict = Verdict {
+                outcome: Outcome::BackendError,
+                headers: &[],
+                status: None,
+                plan: None,
+                token_id: 0,
+                reason: None,
+            };
+            self.release_waiters(key.as_str(), &verdict, service);
+        }
+        self.take_settlement();
+        true
+    }
+}
+
+impl HttpAuthThreescale {
+    fn settle(&mut self, settlement: Settlement) {
+        self.awaiting_verdict = false;
+        if let Some(reason) = settlement.denial {
+            self.decision.get_mut().deny(reason);
+        }
+        if settlement.unvalidated {
+            // the application was not validated, so nothing is reported for it
+            self.auth = None;
+        }
+        self.plan = settlement.plan;
+        self.rate_limits = settlement.rate_limits;
+        if settlement.authorized {
+            self.queue_batched_usage();
+        }
+    }
+
+    // Settles the verdict of the call this request waited for, if it was released.
+    fn take_settlement(&mut self) {
+        if let Some(settlement) = coalesce::settlement(self.context_id) {
+            self.settle(settlement);
+        }
+    }
 
-        let ar = match authrep::authrep(self, &rh) {
+    // Responds to the requests waiting for the call with the key, leaving
+    // each to settle the verdict in its own state once it runs again, and
+    // sends the verdict to the other VMs waiting for it.
+    fn release_waiters(&self, key: &str, verdict: &Verdict, service: Option<&Service>) {
+        let release = coalesce::Release {
+            key: key.to_string(),
+            service_id: service.map(|service| service.id().to_string()),
+            outcome: verdict.outcome,
+            reason: verdict.reason.map(str::to_string),
+        };
+        coalesce::notify(self, self.context_id, &release);
+        let waiters = coalesce::complete(key);
+        if !waiters.is_empty() {
+            debug!(
+                self,
+                "releasing {} coalesced requests ({:?})",
+                waiters.len(),
+                verdict.outcome
+            );
+        }
+        coalesce::for_each_waiter(self, waiters.as_slice(), self.context_id, |waiter| {
+            metrics::record(self, verdict.outcome, service.map(Service::id));
+            coalesce::settle(
+                waiter,
+                verdict::respond(self, self.configuration(), verdict, service),
+            );
+        });
+    }
+
+    // Authorizes the request, either from the cache or by calling 3scale.
+    fn authorize(&mut self, rh: &RequestHeaders) -> FilterHeadersStatus {
+        let ar = match authrep::authrep(self, rh) {
             Err(e) => {
                 error!(self, "error computing authrep {:?}", e);
-                self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                debug!(self, "403 sent");
+                let configuration = Rc::clone(&self.configuration);
+                match e.downcast_ref::<authrep::MatchError>() {
+                    Some(authrep::MatchError::CredentialsError(service_id, _)) => {
+                        metrics::record(self, Outcome::Denied, Some(service_id));
+                        let service = configuration.get().get_service(service_id);
+                        self.send_deny_response(DenyReason::CredentialsMissing, service);
+                    }
+                    Some(authrep::MatchError::NoUsageMatch(service_id)) => {
+                        let service = configuration.get().get_service(service_id);
+                        if let Some(NoMatchBehavior::AllowWithoutReport) =
+                            service.and_then(Service::no_match_behavior)
+                        {
+                            debug!(self, "no mapping rule matched, allowing request");
+                            return FilterHeadersStatus::Continue;
+                        }
+                        metrics::record(self, Outcome::Denied, Some(service_id));
+                        self.send_deny_response(DenyReason::NoMatch, service);
+                    }
+                    _ => {
+                        metrics::record(self, Outcome::Denied, None);
+                        self.send_deny_response(DenyReason::Rejected, None);
+                    }
+                }
                 return FilterHeadersStatus::StopIteration;
             }
             Ok(params) => params,
         };
+        self.decision.borrow_mut().authrep(&ar);
+
+        if ar.service().signature().is_some() {
+            // never forward signatures coming from clients
+            self.set_http_request_header(SIGNATURE_HEADER, None);
+            self.set_http_request_header(TIMESTAMP_HEADER, None);
+        }
+
+        let backend = self.configuration().get_service_backend(ar.service()).ok();
 
         let passthrough_metadata: bool = self.configuration().passthrough_metadata.unwrap_or(false);
 
@@ -64,19 +482,74 @@ impl HttpContext for HttpAuthThreescale {
                 Ok(()) => return FilterHeadersStatus::Continue,
                 Err(e) => {
                     error!(self, "failed to pass app info to next filter: {:?}", e);
-                    self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                    debug!(self, "403 sent");
+                    metrics::record(self, Outcome::Denied, Some(ar.service().id()));
+                    self.send_deny_response(DenyReason::Rejected, Some(ar.service()));
                     return FilterHeadersStatus::StopIteration;
                 }
             }
         }
 
         if let Some(backend) = backend {
+            let cache_key = self
+                .configuration()
+                .cache()
+                .filter(|_| cache::is_cacheable(&ar))
+                .and_then(|config| cache::key(&ar, config.generation()));
+
+            if let Some(key) = cache_key.as_deref() {
+                let cached = cache::get(self, key, self.now());
+                let outcome = if cached.is_some() {
+                    Outcome::CacheHit
+                } else {
+                    Outcome::CacheMiss
+                };
+                metrics::record(self, outcome, Some(ar.service().id()));
+                self.decision.borrow_mut().cache(cached.is_some());
+                let local_limits = self
+                    .configuration()
+                    .cache()
+                    .map_or(false, |config| config.local_limits());
+                let exceeded = match cached {
+                    Some(true) if local_limits => match limits::consume(self, key, self.now()) {
+                        limits::Decision::Exceeded(window) => Some(window),
+                        limits::Decision::Allowed | limits::Decision::Unknown => None,
+                    },
+                    _ => None,
+                };
+                if let Some(window) = exceeded {
+                    debug!(
+                        self,
+                        "on_http_request_headers: limits exceeded from local limits"
+                    );
+                    metrics::record(self, Outcome::LimitsExceeded, Some(ar.service().id()));
+                    let rate_headers = response::rate_limit_headers(&window.headers(self.now()));
+                    self.send_limits_exceeded_response(rate_headers.as_slice(), Some(ar.service()));
+                    return FilterHeadersStatus::StopIteration;
+                }
+                match cached {
+                    Some(true) => {
+                        debug!(self, "on_http_request_headers: authorized from cache");
+                        metrics::record(self, Outcome::Authorized, Some(ar.service().id()));
+                        self.auth = ar.state();
+                        self.queue_batched_usage();
+                        self.sign_request();
+                        return FilterHeadersStatus::Continue;
+                    }
+                    Some(false) => {
+                        debug!(self, "on_http_request_headers: denied from cache");
+                        metrics::record(self, Outcome::Denied, Some(ar.service().id()));
+                        self.send_deny_response(DenyReason::Rejected, Some(ar.service()));
+                        return FilterHeadersStatus::StopIteration;
+                    }
+                    None => (),
+                }
+            }
+
             let request = match authrep::build_call(&ar) {
                 Err(e) => {
                     error!(self, "error computing authrep request {:?}", e);
-                    self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                    debug!(self, "403 sent");
+                    metrics::record(self, Outcome::Denied, Some(ar.service().id()));
+                    self.send_deny_response(DenyReason::Rejected, Some(ar.service()));
                     return FilterHeadersStatus::StopIteration;
                 }
                 Ok(request) => request,
@@ -84,29 +557,94 @@ impl HttpContext for HttpAuthThreescale {
 
             // uri will actually just get the whole path + parameters
             let (uri, body) = request.uri_and_body();
+            let uri = authrep::override_path(ar.service(), &ar.kind(), uri.as_ref());
 
-            let headers = request
+            let mut headers = request
                 .headers
                 .iter()
                 .map(|(key, value)| (key.as_str(), value.as_str()))
                 .collect::<Vec<_>>();
+            let options = authrep::merged_options(headers.as_slice(), authrep::EXTRA_OPTIONS);
+            headers.retain(|(key, _)| !key.eq_ignore_ascii_case(authrep::OPTIONS_HEADER));
+            headers.push((authrep::OPTIONS_HEADER, options.as_str()));
 
-            let upstream = backend.upstream();
-            let call_token = match upstream.call(
-                self,
-                uri.as_ref(),
-                request.method.as_str(),
-                headers,
-                None,
-                body.map(str::as_bytes),
-                None,
-                None,
-            ) {
+            let extra_qs = ar.extra_query_string();
+            let auth = ar.state();
+
+            let call = BackendCall {
+                service_id: ar.service().id().to_string(),
+                path: uri.to_string(),
+                method: request.method.as_str().to_string(),
+                headers: headers
+                    .iter()
+                    .map(|&(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+                extra_qs: extra_qs.clone(),
+                body: body.map(str::to_string),
+                retriable: matches!(ar.kind(), Kind::Authorize),
+                attempt: 0,
+            };
+            // only calls whose usage is not reported can be shared by several requests
+            let coalesce_key = Some(())
+                .filter(|_| self.configuration().coalesce_calls() && cache::is_cacheable(&ar))
+                .and_then(|_| {
+                    let generation = self.configuration().cache().map_or(0, Cache::generation);
+                    cache::key(&ar, generation)
+                });
+            let upstream = ar.service().backend_upstream(backend);
+            if let Some(key) = coalesce_key.as_deref() {
+                // calls whose verdict does not come in time can be sent again by others
+                let timeout_ms = match ar.service().retry_policy() {
+                    Some(policy) if call.retriable => policy.total_timeout_ms(upstream.timeout),
+                    _ => u64::try_from(upstream.timeout.as_millis()).unwrap_or(u64::MAX),
+                };
+                let deadline = self.now().saturating_add(timeout_ms / 1000 + 1);
+                let claim = coalesce::claim(
+                    self,
+                    key,
+                    ar.service().id(),
+                    self.context_id,
+                    self.now(),
+                    deadline,
+                );
+                if claim != Claim::Send {
+                    debug!(
+                        self,
+                        "on_http_request_headers: waiting for an identical authorization in flight ({:?})",
+                        claim
+                    );
+                    self.auth = auth;
+                    // only resumed if authorized, so it can be signed before waiting
+                    self.sign_request();
+                    return FilterHeadersStatus::StopIteration;
+                }
+            }
+
+            let call_token = match call.dispatch(self, upstream, None) {
                 Ok(call_token) => call_token,
                 Err(e) => {
                     error!(self, "on_http_request_headers: could not dispatch HTTP call to {}: did you create the cluster to do so? - {:#?}", upstream.name(), e);
-                    self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-                    debug!(self, "403 sent");
+                    if let Some(key) = coalesce_key.as_deref() {
+                        // others might have joined a call taken over from another VM
+                        let verdict = Verdict {
+                            outcome: Outcome::BackendError,
+                            headers: &[],
+                            status: None,
+                            plan: None,
+                            token_id: 0,
+                            reason: None,
+                        };
+                        self.release_waiters(key, &verdict, Some(ar.service()));
+                    }
+                    metrics::record(self, Outcome::BackendError, Some(ar.service().id()));
+                    if self.fails_open(Some(ar.service())) {
+                        warn!(
+                            self,
+                            "on_http_request_headers: backend unreachable, allowing request"
+                        );
+                        return FilterHeadersStatus::Continue;
+                    }
+                    self.send_deny_response(DenyReason::BackendUnreachable, Some(ar.service()));
                     return FilterHeadersStatus::StopIteration;
                 }
             };
@@ -116,89 +654,534 @@ impl HttpContext for HttpAuthThreescale {
                 "on_http_request_headers: call token is {}", call_token
             );
 
+            // fire and forget: the shadow backend's verdict never affects the request
+            let shadow_token = self.configuration().shadow_backend().and_then(|shadow| {
+                let upstream = shadow.upstream();
+                upstream
+                    .call(
+                        self,
+                        uri.as_ref(),
+                        request.method.as_str(),
+                        headers,
+                        extra_qs.as_deref(),
+                        body.map(str::as_bytes),
+                        None,
+                        None,
+                    )
+                    .map_err(|e| {
+                        warn!(
+                            self,
+                            "on_http_request_headers: could not dispatch HTTP call to shadow backend {}: {:#?}",
+                            upstream.name(),
+                            e
+                        );
+                    })
+                    .ok()
+            });
+            self.shadow.token = shadow_token;
+            self.backend_call = Some(call);
+            self.coalesce_key = coalesce_key;
+            self.auth = auth;
+            self.cache_key = cache_key;
+            self.backend_call_started = Some(self.get_current_time());
+
             FilterHeadersStatus::StopIteration
         } else {
             // no backend configured
-            debug!(self, "on_http_request_headers: no backend configured");
-            self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-            debug!(self, "403 sent");
+            debug!(
+                self,
+                "on_http_request_headers: no backend configured for service {}",
+                ar.service().id()
+            );
+            metrics::record(self, Outcome::BackendError, Some(ar.service().id()));
+            self.send_deny_response(DenyReason::BackendUnreachable, Some(ar.service()));
             FilterHeadersStatus::StopIteration
         }
     }
 
-    fn on_http_response_headers(&mut self, _: usize) -> FilterHeadersStatus {
-        self.set_http_response_header("Powered-By", Some("3scale"));
-        FilterHeadersStatus::Continue
+    // Dispatches the call to the backend again if the retry policy of its
+    // service allows it, returning whether it did.
+    fn retry_backend_call(&mut self) -> bool {
+        let mut call = match self.backend_call.take() {
+            Some(call) if call.retriable => call,
+            // 3scale might have reported the usage of a call that timed out
+            _ => return false,
+        };
+        let configuration = Rc::clone(&self.configuration);
+        let config = configuration.get();
+        let service = config.get_service(call.service_id.as_str());
+        let policy = match service.and_then(Service::retry_policy) {
+            Some(policy) if call.attempt + 1 < policy.max_attempts() => policy,
+            _ => return false,
+        };
+        let upstream = match service.map(|service| (service, config.get_service_backend(service))) {
+            Some((service, Ok(backend))) => service.backend_upstream(backend),
+            _ => return false,
+        };
+
+        call.attempt += 1;
+        let timeout_ms = policy.timeout_ms(upstream.timeout, call.attempt);
+        match call.dispatch(self, upstream, Some(timeout_ms)) {
+            Ok(token) => {
+                warn!(
+                    self,
+                    "backend call failed, retrying ({} of {}) with call token {}",
+                    call.attempt,
+                    policy.max_attempts() - 1,
+                    token
+                );
+                self.backend_call = Some(call);
+                self.backend_call_started = Some(self.get_current_time());
+                true
+            }
+            Err(e) => {
+                error!(self, "could not dispatch retry of backend call: {:#?}", e);
+                false
+            }
+        }
     }
-}
 
-impl Context for HttpAuthThreescale {
-    fn on_http_call_response(&mut self, token_id: u32, _: usize, _: usize, _: usize) {
-        info!(
+    // Strips the headers of the request that its service tells to, so that
+    // clients can't preset them, returning the headers left.
+    fn sanitize_request(&mut self, rh: RequestHeaders) -> RequestHeaders {
+        let sanitize = match authrep::find_service(self, &rh)
+            .ok()
+            .and_then(Service::sanitize)
+        {
+            Some(sanitize) => sanitize.clone(),
+            None => return rh,
+        };
+        let stripped = rh
+            .iter()
+            .filter(|(name, _)| sanitize.strips_request_header(name.as_str()))
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        let any_stripped = !stripped.is_empty();
+        for name in stripped {
+            debug!(self, "on_http_request_headers: stripping header {}", name);
+            self.set_http_request_header(name, None);
+        }
+
+        self.sanitize = Some(sanitize);
+        if any_stripped {
+            RequestHeaders::new(self)
+        } else {
+            rh
+        }
+    }
+
+    // Lets requests matching the bypass rules of their service through without
+    // looking up credentials, reporting their usage for the anonymous
+    // application if there is one, unless the client used up its local
+    // limits. Returns how to go on with a bypassed request.
+    fn bypass(&mut self, rh: &RequestHeaders) -> Option<FilterHeadersStatus> {
+        let url = rh.url().ok()?;
+        let metadata = rh.metadata();
+        let method = metadata.method();
+        let service = authrep::find_service(self, rh).ok()?;
+        let bypass = service
+            .bypass()
+            .filter(|bypass| bypass.is_match(method, url.path()))?;
+        debug!(
             self,
-            "http_ctx: on_http_call_response: token id is {}", token_id
+            "on_http_request_headers: {} {} bypasses authorization",
+            method,
+            url.path()
         );
-        let authorized = self
-            .get_http_call_response_headers()
-            .into_iter()
-            .find(|(key, _)| key.as_str() == ":status")
-            .map_or(false, |(_, value)| value.as_str() == "200");
 
-        if authorized {
-            info!(self, "on_http_call_response: authorized {}", token_id);
-            self.resume_http_request();
+        if let Some(limit) = bypass.client_ip_limit() {
+            let address = match limit.header() {
+                Some(header) => rh.get(header).map(str::to_string),
+                None => self
+                    .get_property(vec!["source", "address"])
+                    .map(|address| String::from_utf8_lossy(address.as_slice()).into_owned()),
+            };
+            match address
+                .as_deref()
+                .and_then(|address| limit.client_ip(address))
+            {
+                Some(client_ip) => {
+                    let cache = self.configuration().cache().cloned().unwrap_or_default();
+                    let key = cache::client_key(
+                        service.id(),
+                        bypass.anonymous_app().unwrap_or_default(),
+                        client_ip,
+                        cache.generation(),
+                    );
+                    let now = self.now();
+                    let (decision, started) = limits::consume_or_start(
+                        self,
+                        key.as_str(),
+                        limit.requests,
+                        limit.period,
+                        now,
+                    );
+                    if started {
+                        cache::touch_key(self, &cache, key.as_str());
+                    }
+                    if let limits::Decision::Exceeded(window) = decision {
+                        debug!(
+                            self,
+                            "on_http_request_headers: client {} exceeded its local limits",
+                            client_ip
+                        );
+                        metrics::record(self, Outcome::LimitsExceeded, Some(service.id()));
+                        let rate_headers = response::rate_limit_headers(&window.headers(now));
+                        self.send_limits_exceeded_response(rate_headers.as_slice(), Some(service));
+                        return Some(FilterHeadersStatus::StopIteration);
+                    }
+                }
+                // limits can't be told apart per client, so let the request go unlimited
+                None => warn!(
+                    self,
+                    "on_http_request_headers: no client address to limit anonymous requests by"
+                ),
+            }
+        }
+
+        let token = bypass.anonymous_app().and_then(|app_id| {
+            let mut pattern = url.path().to_string();
+            if let Some(qs) = url.query() {
+                pattern.push('?');
+                pattern.push_str(qs);
+            }
+            let usage = authrep::bypass_usage(self, service, method, pattern.as_str());
+            if usage.is_empty() {
+                return None;
+            }
+            let app = Application::AppId(app_id.into(), None);
+            self.report_app(service, &app, usage.as_slice())
+                .map_err(|e| warn!(self, "failed to report anonymous usage: {:?}", e))
+                .ok()
+        });
+        if let Some(token) = token {
+            self.reports.push(token);
+        }
+        Some(FilterHeadersStatus::Continue)
+    }
+
+    // Size of the body to buffer before authorizing, if credentials are to be looked up in it.
+    fn body_limit(&self, rh: &RequestHeaders) -> Option<usize> {
+        let limit = authrep::find_service(self, rh)
+            .ok()
+            .and_then(|service| service.credentials().body_limit())?;
+        if rh.get("transfer-encoding").is_some() {
+            return Some(limit);
+        }
+        if let Some(length) = rh.get("content-length") {
+            return Some(limit).filter(|_| length != "0");
+        }
+        // only HTTP/1 requires either header for requests to have a body, so
+        // others wait for it unless their credentials are found elsewhere
+        let protocol = self.get_property(vec!["request", "protocol"]);
+        let http1 = protocol.map_or(false, |protocol| protocol.starts_with(b"HTTP/1"));
+        if http1 {
+            return None;
+        }
+        match authrep::authrep(self, rh) {
+            Err(e) => match e.downcast_ref::<authrep::MatchError>() {
+                Some(authrep::MatchError::CredentialsError(..)) => Some(limit),
+                _ => None,
+            },
+            Ok(_) => None,
+        }
+    }
+
+    pub fn request_body(&self) -> Option<&[u8]> {
+        self.request_body.as_deref()
+    }
+
+    fn fails_open(&self, service: Option<&Service>) -> bool {
+        verdict::fails_open(self.configuration(), service)
+    }
+
+    fn send_deny_response(&self, reason: DenyReason, service: Option<&Service>) {
+        let denial = verdict::send_deny_response(self, self.configuration(), reason, service);
+        self.decision.borrow_mut().deny(denial);
+    }
+
+    fn limits_exceeded(
+        &self,
+        headers: &[(String, String)],
+        status: Option<&str>,
+        body_size: usize,
+    ) -> bool {
+        // 3scale answers with a conflict when applications are denied
+        if status != Some("409") {
+            return false;
+        }
+
+        let reason = headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("3scale-rejection-reason"))
+            .map(|(_, value)| value.as_str());
+        let body = if reason.is_none() && body_size > 0 {
+            self.get_http_call_response_body(0, body_size)
+        } else {
+            None
+        };
+        let body = body
+            .as_ref()
+            .map(|body| String::from_utf8_lossy(body.as_slice()));
+
+        response::limits_exceeded(reason, body.as_deref())
+    }
+
+    fn rejection_reason(&self, headers: &[(String, String)], body_size: usize) -> Option<String> {
+        let header = headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("3scale-rejection-reason"))
+            .map(|(_, value)| value.as_str());
+        let body = if header.is_none() && body_size > 0 {
+            self.get_http_call_response_body(0, body_size)
         } else {
-            info!(self, "on_http_call_response: forbidden {}", token_id);
-            self.send_http_response(403, vec![], Some(b"Access forbidden.\n"));
-            debug!(self, "403 sent");
+            None
+        };
+        let body = body
+            .as_ref()
+            .map(|body| String::from_utf8_lossy(body.as_slice()));
+
+        response::rejection_reason(header, body.as_deref()).map(|reason| reason.into_owned())
+    }
+
+    fn send_rejection_response(&self, reason: Option<&str>, service: Option<&Service>) {
+        let denial = verdict::send_rejection_response(self, self.configuration(), reason, service);
+        self.decision.borrow_mut().deny(denial);
+    }
+
+    fn send_limits_exceeded_response(
+        &self,
+        rate_headers: &[(String, String)],
+        service: Option<&Service>,
+    ) {
+        let denial = verdict::send_limits_exceeded_response(
+            self,
+            self.configuration(),
+            rate_headers,
+            service,
+        );
+        self.decision.borrow_mut().deny(denial);
+    }
+
+    // seconds since the UNIX epoch
+    fn now(&self) -> u64 {
+        self.get_current_time()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs())
+    }
+
+    fn compare_shadow_verdicts(&self) {
+        if let Some((primary, shadow)) = self.shadow.mismatch() {
+            warn!(
+                self,
+                "shadow backend mismatch: primary authorized {}, shadow authorized {}",
+                primary,
+                shadow
+            );
+            metrics::increment_counter(self, "shadow_mismatch_total");
         }
     }
-}
 
-impl HttpAuthThreescale {
-    fn threescale_info_to_metadata(&self, ar: &authrep::AuthRep) -> Result<(), anyhow::Error> {
-        let apps = ar.apps();
-        let service = ar.service();
-        let usages = ar.usages();
+    fn report_response_bytes(&mut self) {
+        let auth = match self.auth.as_ref() {
+            Some(auth) if !auth.response_metrics().is_empty() => auth,
+            _ => return,
+        };
 
-        if apps.is_empty() {
-            anyhow::bail!("could not extract application credentials");
+        if self.response_bytes == 0 {
+            return;
         }
 
-        let backend = self.configuration().backend();
-        if backend.is_none() {
-            anyhow::bail!("backend not configured");
+        let bytes = self.response_bytes.to_string();
+        let usage = auth
+            .response_metrics()
+            .iter()
+            .map(|metric| (metric.clone(), bytes.clone()))
+            .collect::<Vec<_>>();
+
+        match self.report(auth, usage.as_slice()) {
+            Ok(token) => self.reports.push(token),
+            Err(e) => warn!(self, "failed to report response body size: {:?}", e),
         }
-        let upstream = backend.unwrap().upstream();
+    }
 
-        let mut app_id_key = String::new();
-        let (header, value) = match &apps[0] {
-            Application::AppId(app_id, app_key) => {
-                app_id_key.push_str(app_id.as_ref());
-                if let Some(key) = app_key {
-                    app_id_key.push(':');
-                    app_id_key.push_str(key.as_ref());
-                }
-                ("x-3scale-app-id", app_id_key.as_str())
+    // Reports the usage of the response rules of the service matching the
+    // response, whatever its status.
+    fn report_response_rules_usage(&mut self) {
+        let auth = match self.auth.as_ref() {
+            Some(auth) if auth.is_metered() => auth,
+            _ => return,
+        };
+        let configuration = Rc::clone(&self.configuration);
+        let rules = match configuration
+            .get()
+            .get_service(auth.service_id())
+            .and_then(Service::response_rules)
+        {
+            Some(rules) => rules,
+            None => return,
+        };
+
+        let status = self.get_http_response_header(":status").unwrap_or_default();
+        let headers = self.get_http_response_headers();
+        let usage = response_rule::usage(rules, status.as_str(), headers.as_slice());
+        if usage.is_empty() {
+            return;
+        }
+
+        match self.report(auth, usage.as_slice()) {
+            Ok(token) => self.reports.push(token),
+            Err(e) => warn!(self, "failed to report response usage: {:?}", e),
+        }
+    }
+
+    // gRPC responses tell their status in grpc-status, which is found in the
+    // trailers unless the response carries no messages.
+    fn report_deferred_usage(&mut self, trailers: bool) {
+        let auth = match self.auth.as_ref() {
+            Some(auth) if !auth.deferred_usage().is_empty() => auth,
+            _ => return,
+        };
+
+        let grpc = self
+            .configuration()
+            .get_service(auth.service_id())
+            .map_or(false, Service::is_grpc);
+        let status = match (grpc, trailers) {
+            (true, true) => self.get_http_response_trailer("grpc-status"),
+            (true, false) => match self.get_http_response_header("grpc-status") {
+                Some(status) => Some(status),
+                // wait for the trailers
+                None => return,
+            },
+            (false, false) => self.get_http_response_header(":status"),
+            (false, true) => return,
+        };
+        let succeeded = status.as_deref().map_or(false, |status| {
+            if grpc {
+                status.trim() == "0"
+            } else {
+                status.starts_with('2')
             }
-            Application::UserKey(user_key) => ("x-3scale-user-key", user_key.as_ref()),
-            Application::OAuthToken(_token) => anyhow::bail!("Oauth token not supported"),
+        });
+        if !succeeded {
+            debug!(
+                self,
+                "not reporting usage for unsuccessful response with status {:?}", status
+            );
+            return;
+        }
+
+        // not waited for, so the response is never held back by the report
+        match self.report(auth, auth.deferred_usage()) {
+            Ok(token) => self.reports.push(token),
+            Err(e) => warn!(self, "failed to report usage: {:?}", e),
+        }
+    }
+
+    fn queue_batched_usage(&mut self) {
+        let max_pending = match self.configuration().batching() {
+            Some(batching) => batching.max_pending(),
+            None => return,
+        };
+        let auth = match self.auth.as_ref() {
+            Some(auth) if !auth.batched_usage().is_empty() => auth,
+            _ => return,
         };
 
-        // Adding threescale info as request headers
-        self.add_http_request_header(header, value);
-        self.add_http_request_header("x-3scale-cluster-name", upstream.name());
-        self.add_http_request_header("x-3scale-upstream-url", upstream.url.as_str());
-        self.add_http_request_header("x-3scale-timeout", &upstream.default_timeout().to_string());
-        self.add_http_request_header("x-3scale-service-id", service.id());
-        let service_token = if let Some(token) = service.token() {
-            token
-        } else {
-            // without service token, other info is useless.
-            anyhow::bail!("service token unavailable");
+        match batch::add(self, auth.service_id(), auth.app(), auth.batched_usage()) {
+            // don't wait for the next tick with a full queue
+            Ok(pending) if pending >= max_pending => {
+                let configuration = Rc::clone(&self.configuration);
+                batch::flush(self, configuration.get());
+            }
+            Ok(_) => (),
+            Err(e) => warn!(self, "failed to queue usage for batch reporting: {:?}", e),
+        }
+    }
+
+    fn report(
+        &self,
+        auth: &authrep::AuthState,
+        usage: &[(String, String)],
+    ) -> Result<u32, anyhow::Error> {
+        let service = self
+            .configuration()
+            .get_service(auth.service_id())
+            .ok_or_else(|| anyhow::anyhow!("service {} not found", auth.service_id()))?;
+        self.report_app(service, auth.app(), usage)
+    }
+
+    fn report_app(
+        &self,
+        service: &Service,
+        app: &Application,
+        usage: &[(String, String)],
+    ) -> Result<u32, anyhow::Error> {
+        let backend = self.configuration().get_service_backend(service)?;
+        let request = authrep::build_report(service, app, usage)?;
+        authrep::send_report(self, backend, service, &request)
+    }
+
+    fn plan_from_response(&self, body_size: usize) -> Option<String> {
+        let wants_plan = self
+            .auth
+            .as_ref()
+            .and_then(|auth| self.configuration().get_service(auth.service_id()))
+            .map_or(false, |service| service.plan_header());
+        if !wants_plan || body_size == 0 {
+            return None;
+        }
+
+        let body = self.get_http_call_response_body(0, body_size)?;
+        let plan = response::plan(String::from_utf8_lossy(body.as_slice()).as_ref())
+            .map(|plan| plan.into_owned());
+        if plan.is_none() {
+            debug!(self, "could not find a plan in the authorization response");
+        }
+        plan
+    }
+
+    fn sign_request(&self) {
+        let auth = match self.auth.as_ref() {
+            Some(auth) => auth,
+            None => return,
         };
-        self.add_http_request_header("x-3scale-service-token", service_token);
-        self.add_http_request_header("x-3scale-usages", &serde_json::to_string(&usages)?);
+
+        let signature = self
+            .configuration()
+            .get_service(auth.service_id())
+            .and_then(|service| service.signature());
+
+        if let Some(signature) = signature {
+            let timestamp = self.now();
+            let value = signature::sign(
+                signature.secret(),
+                auth.service_id(),
+                auth.app_id(),
+                timestamp,
+            );
+            self.set_http_request_header(SIGNATURE_HEADER, Some(value.as_str()));
+            self.set_http_request_header(TIMESTAMP_HEADER, Some(timestamp.to_string().as_str()));
+        }
+    }
+
+    fn threescale_info_to_metadata(&self, ar: &authrep::AuthRep) -> Result<(), anyhow::Error> {
+        let backend = self.configuration().get_service_backend(ar.service())?;
+        let upstream = ar.service().backend_upstream(backend);
+
+        // properties are not seen upstream nor can they be spoofed by clients
+        if let Some(namespace) = self.configuration().metadata_namespace() {
+            for (name, value) in authrep::passthrough_properties(ar, upstream)? {
+                self.set_property(vec![namespace, name], Some(value.as_bytes()));
+            }
+            return Ok(());
+        }
+
+        // Adding threescale info as request headers
+        for (header, value) in authrep::passthrough_headers(ar, upstream)? {
+            self.add_http_request_header(header, value.as_str());
+        }
         Ok(())
     }
 }
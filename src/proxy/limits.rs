@@ -0,0 +1,228 @@
+// Local bookkeeping of the usage limits of applications.
+//
+// With the limit headers extension, 3scale tells how many more requests like
+// an authorized one the application can make before the most constrained of
+// its limits resets. Apisonator computes this for the metrics hit and their
+// ancestors in the metric hierarchy, so a method eating into the limits of
+// its parent metric is also accounted for. The window is kept next to the
+// cached verdict, and requests authorized from the cache consume from it, so
+// that applications running out of it are denied locally until it resets
+// instead of until the verdict expires.
+use proxy_wasm::traits::Context;
+use proxy_wasm::types::Status;
+
+use crate::log::IdentLogger;
+
+const PREFIX: &str = "3scale-limits/";
+const MAX_CAS_RETRIES: usize = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Window {
+    // requests left until the reset
+    remaining: u64,
+    // seconds since the UNIX epoch
+    reset_at: u64,
+}
+
+// Whether a request fits in the limits of its application.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Allowed,
+    Exceeded(Window),
+    // no window is known, or it was reset
+    Unknown,
+}
+
+impl Window {
+    // 3scale sends negative values for applications without limits.
+    pub fn from_headers(headers: &[(String, String)], now: u64) -> Option<Self> {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| value.trim().parse::<u64>().ok())
+        };
+        let remaining = header("3scale-limit-remaining")?;
+        let reset = header("3scale-limit-reset")?;
+
+        Some(Self {
+            remaining,
+            reset_at: now.saturating_add(reset),
+        })
+    }
+
+    pub fn new(requests: u64, period: u64, now: u64) -> Self {
+        Self {
+            remaining: requests,
+            reset_at: now.saturating_add(period),
+        }
+    }
+
+    pub fn consume(&mut self, now: u64) -> Decision {
+        if now >= self.reset_at {
+            Decision::Unknown
+        } else if self.remaining == 0 {
+            Decision::Exceeded(*self)
+        } else {
+            self.remaining -= 1;
+            Decision::Allowed
+        }
+    }
+
+    // The limit headers a 3scale denial would have, for clients.
+    pub fn headers(&self, now: u64) -> Vec<(String, String)> {
+        vec![
+            (
+                "3scale-limit-remaining".to_string(),
+                self.remaining.to_string(),
+            ),
+            (
+                "3scale-limit-reset".to_string(),
+                self.reset_at.saturating_sub(now).to_string(),
+            ),
+        ]
+    }
+
+    fn encode(&self) -> [u8; 16] {
+        let mut entry = [0u8; 16];
+        entry[..8].copy_from_slice(&self.remaining.to_be_bytes());
+        entry[8..].copy_from_slice(&self.reset_at.to_be_bytes());
+        entry
+    }
+
+    fn decode(entry: &[u8]) -> Option<Self> {
+        if entry.len() != 16 {
+            return None;
+        }
+        let mut remaining = [0u8; 8];
+        let mut reset_at = [0u8; 8];
+        remaining.copy_from_slice(&entry[..8]);
+        reset_at.copy_from_slice(&entry[8..]);
+
+        Some(Self {
+            remaining: u64::from_be_bytes(remaining),
+            reset_at: u64::from_be_bytes(reset_at),
+        })
+    }
+}
+
+// The window of the requests sharing a cache key.
+pub fn key(cache_key: &str) -> String {
+    format!("{}{}", PREFIX, cache_key)
+}
+
+// Stores the window from the headers of an authorization, clearing any
+// previous one if there are no limits.
+pub fn set<C: Context + IdentLogger>(
+    ctx: &C,
+    cache_key: &str,
+    headers: &[(String, String)],
+    now: u64,
+) {
+    let entry = Window::from_headers(headers, now).map(|window| window.encode());
+    let entry = entry.as_ref().map_or(&[] as &[u8], |entry| &entry[..]);
+    if let Err(e) = ctx.set_shared_data(key(cache_key).as_str(), Some(entry), None) {
+        warn!(ctx, "failed to store usage limits: {:?}", e);
+    }
+}
+
+pub fn consume<C: Context + IdentLogger>(ctx: &C, cache_key: &str, now: u64) -> Decision {
+    let key = key(cache_key);
+    let mut retries = 0;
+    loop {
+        let (data, cas) = ctx.get_shared_data(key.as_str());
+        let mut window = match data.as_deref().and_then(Window::decode) {
+            Some(window) => window,
+            None => return Decision::Unknown,
+        };
+        let decision = window.consume(now);
+        if decision != Decision::Allowed {
+            return decision;
+        }
+        match ctx.set_shared_data(key.as_str(), Some(&window.encode()[..]), cas) {
+            Ok(()) => return decision,
+            Err(Status::CasMismatch) if retries < MAX_CAS_RETRIES => retries += 1,
+            Err(e) => {
+                debug!(ctx, "failed to update usage limits: {:?}", e);
+                return decision;
+            }
+        }
+    }
+}
+
+// Consumes from a window counted locally, starting a new one of the given
+// number of requests over the period in seconds when none is running, and
+// telling whether it did.
+pub fn consume_or_start<C: Context + IdentLogger>(
+    ctx: &C,
+    cache_key: &str,
+    requests: u64,
+    period: u64,
+    now: u64,
+) -> (Decision, bool) {
+    let key = key(cache_key);
+    let mut retries = 0;
+    loop {
+        let (data, cas) = ctx.get_shared_data(key.as_str());
+        let current = data
+            .as_deref()
+            .and_then(Window::decode)
+            .filter(|window| now < window.reset_at);
+        let started = current.is_none();
+        let mut window = current.unwrap_or_else(|| Window::new(requests, period, now));
+        let decision = window.consume(now);
+        if decision != Decision::Allowed {
+            return (decision, false);
+        }
+        match ctx.set_shared_data(key.as_str(), Some(&window.encode()[..]), cas) {
+            Ok(()) => return (decision, started),
+            Err(Status::CasMismatch) if retries < MAX_CAS_RETRIES => retries += 1,
+            Err(e) => {
+                debug!(ctx, "failed to update local limits: {:?}", e);
+                return (decision, false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn headers(remaining: &str, reset: &str) -> Vec<(String, String)> {
+        vec![
+            ("3scale-limit-remaining".to_string(), remaining.to_string()),
+            ("3scale-limit-reset".to_string(), reset.to_string()),
+        ]
+    }
+
+    #[test]
+    fn windows_run_out_until_reset() {
+        let mut window = Window::from_headers(&headers("2", "30"), 100).unwrap();
+
+        assert_eq!(window.consume(101), Decision::Allowed);
+        assert_eq!(window.consume(102), Decision::Allowed);
+        assert_eq!(window.consume(103), Decision::Exceeded(window));
+        assert_eq!(window.headers(103), headers("0", "27"));
+        assert_eq!(window.consume(130), Decision::Unknown);
+    }
+
+    #[test]
+    fn local_windows_start_full() {
+        let mut window = Window::new(1, 60, 100);
+
+        assert_eq!(window.consume(100), Decision::Allowed);
+        assert_eq!(window.consume(159), Decision::Exceeded(window));
+        assert_eq!(window.consume(160), Decision::Unknown);
+    }
+
+    #[test]
+    fn windows_need_limits() {
+        assert_eq!(Window::from_headers(&headers("-1", "-1"), 100), None);
+        assert_eq!(Window::from_headers(&[], 100), None);
+
+        let window = Window::from_headers(&headers("5", "60"), 100).unwrap();
+        assert_eq!(Window::decode(&window.encode()), Some(window));
+        assert_eq!(Window::decode(&[]), None);
+    }
+}
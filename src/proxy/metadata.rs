@@ -55,6 +55,13 @@ impl Metadata {
         Self::try_from(buffer)
     }
 
+    #[cfg(test)]
+    pub fn from_filter(filter: &str, st: ProtoStruct) -> Self {
+        let mut filter_metadata = std::collections::HashMap::new();
+        filter_metadata.insert(filter.to_string(), st);
+        Self { filter_metadata }
+    }
+
     pub fn get_filter(&self, key: &str) -> Option<&ProtoStruct> {
         self.filter_metadata.get(key)
     }
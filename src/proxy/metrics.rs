@@ -0,0 +1,157 @@
+// Metrics exposed through the host's stats sink.
+//
+// The SDK version we use does not wrap the metrics part of the ABI, so we
+// call into the host directly. Metrics are defined lazily the first time
+// they are used and their ids are cached per thread, so they are never
+// registered again once defined.
+use core::convert::TryFrom;
+use core::time::Duration;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::log::IdentLogger;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("host returned status {0} when defining metric {1}")]
+    Define(u32, String),
+}
+
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MetricType {
+    Counter = 0,
+    #[allow(dead_code)]
+    Gauge = 1,
+    Histogram = 2,
+}
+
+mod abi {
+    extern "C" {
+        pub fn proxy_define_metric(
+            metric_type: u32,
+            name_data: *const u8,
+            name_size: usize,
+            return_id: *mut u32,
+        ) -> u32;
+        pub fn proxy_increment_metric(metric_id: u32, offset: i64) -> u32;
+        pub fn proxy_record_metric(metric_id: u32, value: u64) -> u32;
+    }
+}
+
+thread_local! {
+    static METRICS: RefCell<HashMap<String, Option<u32>>> = RefCell::new(HashMap::new());
+}
+
+fn define(metric_type: MetricType, name: &str) -> Result<u32, Error> {
+    let mut id = 0u32;
+    // Safety: the host only reads name_size bytes from name_data and writes the id.
+    let status =
+        unsafe { abi::proxy_define_metric(metric_type as u32, name.as_ptr(), name.len(), &mut id) };
+    match status {
+        0 => Ok(id),
+        status => Err(Error::Define(status, name.to_string())),
+    }
+}
+
+// Returns the id for the metric, defining it if needed. Failures are logged
+// once and remembered so that we don't keep asking the host.
+fn get_or_define(ctx: &dyn IdentLogger, metric_type: MetricType, name: &str) -> Option<u32> {
+    METRICS.with(|metrics| {
+        let mut metrics = metrics.borrow_mut();
+        if let Some(id) = metrics.get(name) {
+            return *id;
+        }
+        let id = match define(metric_type, name) {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!(ctx, "failed to define metric: {}", e);
+                None
+            }
+        };
+        metrics.insert(name.to_string(), id);
+        id
+    })
+}
+
+// Outcomes of the authorization of requests.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Authorized,
+    Denied,
+    LimitsExceeded,
+    BackendError,
+    CacheHit,
+    CacheMiss,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Authorized => "authorized",
+            Self::Denied => "denied",
+            Self::LimitsExceeded => "limits_exceeded",
+            Self::BackendError => "backend_error",
+            Self::CacheHit => "cache_hit",
+            Self::CacheMiss => "cache_miss",
+        }
+    }
+}
+
+// Names are prefixed with the service when it is known, so that the service
+// id can be extracted as a tag, ie. "3scale.service.123.authorized".
+fn name(metric: &str, service_id: Option<&str>) -> String {
+    match service_id {
+        Some(id) => format!("3scale.service.{}.{}", id, metric),
+        None => format!("3scale.{}", metric),
+    }
+}
+
+pub fn record(ctx: &dyn IdentLogger, outcome: Outcome, service_id: Option<&str>) {
+    increment_counter(ctx, name(outcome.as_str(), service_id).as_str());
+}
+
+pub fn record_denial(ctx: &dyn IdentLogger, reason: &str, service_id: Option<&str>) {
+    let metric = format!("denied_by_reason.{}", reason);
+    increment_counter(ctx, name(metric.as_str(), service_id).as_str());
+}
+
+pub fn record_backend_latency(ctx: &dyn IdentLogger, latency: Duration, service_id: Option<&str>) {
+    let millis = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+    record_histogram(ctx, name("backend_latency_ms", service_id).as_str(), millis);
+}
+
+pub fn record_reports_flushed(ctx: &dyn IdentLogger, service_id: Option<&str>) {
+    increment_counter(ctx, name("reports_flushed", service_id).as_str());
+}
+
+pub fn record_config_reload_failed(ctx: &dyn IdentLogger) {
+    increment_counter(ctx, name("config_reload_failed", None).as_str());
+}
+
+pub fn increment_counter(ctx: &dyn IdentLogger, name: &str) {
+    add_counter(ctx, name, 1);
+}
+
+pub fn record_histogram(ctx: &dyn IdentLogger, name: &str, value: u64) {
+    if let Some(id) = get_or_define(ctx, MetricType::Histogram, name) {
+        // Safety: no pointers are involved.
+        let status = unsafe { abi::proxy_record_metric(id, value) };
+        if status != 0 {
+            debug!(ctx, "failed to record metric {}: status {}", name, status);
+        }
+    }
+}
+
+pub fn add_counter(ctx: &dyn IdentLogger, name: &str, offset: i64) {
+    if let Some(id) = get_or_define(ctx, MetricType::Counter, name) {
+        // Safety: no pointers are involved.
+        let status = unsafe { abi::proxy_increment_metric(id, offset) };
+        if status != 0 {
+            debug!(
+                ctx,
+                "failed to increment metric {}: status {}", name, status
+            );
+        }
+    }
+}
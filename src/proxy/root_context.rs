@@ -2,29 +2,40 @@ use proxy_wasm::traits::{Context, RootContext};
 use proxy_wasm::types::{BufferType, ChildContext};
 
 use core::time::Duration;
+use std::rc::Rc;
 use std::time::SystemTime;
 
-use crate::configuration::Configuration;
-use crate::log::IdentLogger;
+use crate::configuration::{validation, Configuration};
+use crate::log::{IdentLogger, LogLevel};
 use crate::proxy::config_fetcher::{self, proxy, ConfigFetcher, Fetcher, FetcherState};
-use crate::threescale::{MappingRule, Usage};
+use crate::threescale::{Environment, MappingRule, Service, Usage};
 use crate::util::rand::thread_rng::{thread_rng_init_fallible, ThreadRng};
 use crate::util::serde::ErrorLocation;
 
 use threescalers::http::mapping_rule::{Method, RestRule};
 
+use super::batch;
+use super::coalesce;
 use super::http_context::HttpAuthThreescale;
+use super::metrics;
+use super::verdict::{self, Verdict};
 
 const MIN_SYNC: u64 = 20;
+// how often waits for calls sent from other VMs are checked for expiration
+const COALESCE_SWEEP: u64 = 1;
+// raw plugin configuration last loaded successfully by any VM
+const LAST_GOOD_CONFIG_KEY: &str = "3scale-last-good-config";
 
 pub(super) struct RootAuthThreescale {
     vm_configuration: Option<Vec<u8>>,
-    configuration: Option<Configuration>,
+    configuration: Option<Rc<Configuration>>,
     rng: ThreadRng,
     context_id: u32,
     id: u32,
     log_id: String,
     config_deadline: SystemTime,
+    // batched usage is not flushed on ticks before this time
+    flush_deadline: SystemTime,
 }
 
 impl RootAuthThreescale {
@@ -37,8 +48,64 @@ impl RootAuthThreescale {
             id: 0,
             log_id: String::new(),
             config_deadline: std::time::UNIX_EPOCH,
+            flush_deadline: std::time::UNIX_EPOCH,
         }
     }
+
+    // Parses and validates a configuration, logging all the problems found.
+    fn parse_configuration(&self, buf: &[u8]) -> Option<Configuration> {
+        use core::convert::TryFrom;
+
+        let conf_str = String::from_utf8_lossy(buf);
+        let show_error = |e: &<Configuration as TryFrom<&[u8]>>::Error| {
+            if let Ok(el) = ErrorLocation::try_from(e) {
+                for line in el.error_lines(conf_str.as_ref(), 4, 4) {
+                    error!(self, "{}", line);
+                }
+            } else {
+                // not a configuration syntax/data error (ie. programmatic)
+                error!(self, "fatal configuration error: {:#?}", e);
+            }
+        };
+
+        let document = match validation::document(buf) {
+            Ok(document) => document,
+            Err(e) => {
+                show_error(&e);
+                return None;
+            }
+        };
+        let conf = Configuration::try_from(buf);
+        let diagnostics = validation::validate(&document, conf.as_ref().ok());
+        for diagnostic in diagnostics.iter() {
+            if diagnostic.is_warning() {
+                warn!(self, "configuration warning at {}", diagnostic);
+            } else {
+                error!(self, "invalid configuration at {}", diagnostic);
+            }
+        }
+        let valid = diagnostics.iter().all(validation::Diagnostic::is_warning);
+
+        match conf {
+            Ok(conf) if valid => Some(conf),
+            Ok(_) => None,
+            Err(e) => {
+                show_error(&e);
+                None
+            }
+        }
+    }
+
+    // The configuration kept by the VM that last loaded one successfully,
+    // for VMs started with a configuration that fails validation.
+    fn last_good_configuration(&self) -> Option<Configuration> {
+        use core::convert::TryFrom;
+
+        let data = self.get_shared_data(LAST_GOOD_CONFIG_KEY).0?;
+        Configuration::try_from(data.as_slice())
+            .map_err(|e| error!(self, "failed to parse last known good configuration: {}", e))
+            .ok()
+    }
 }
 
 impl IdentLogger for RootAuthThreescale {
@@ -49,7 +116,6 @@ impl IdentLogger for RootAuthThreescale {
 
 impl Context for RootAuthThreescale {
     fn on_registered(&mut self, context_id: u32) {
-        use crate::log::LogLevel;
         use core::fmt::Write as _;
 
         self.context_id = context_id;
@@ -105,6 +171,11 @@ impl Context for RootAuthThreescale {
         _body_size: usize,
         _num_trailers: usize,
     ) {
+        let status = self.get_http_call_response_header(":status");
+        if batch::complete(self, token_id, status.as_deref()) {
+            return;
+        }
+
         if let Some(sys) = self.get_system_config() {
             let upstream = sys.upstream();
             let qs_params = format!("access_token={}", sys.token());
@@ -130,11 +201,12 @@ impl Context for RootAuthThreescale {
                 let cf = vcf.get_mut(idx).unwrap();
                 let mut rules_updated: bool = false;
 
-                let config = self.configuration.as_mut().map(|config| config.get_mut());
-                let services_op = config.and_then(|config| config.services.as_mut());
-                let services = services_op.unwrap(); // cannot make a callout without services
+                let known_service = self
+                    .get_configuration()
+                    .and_then(|config| config.get_service(cf.service_id()))
+                    .is_some();
 
-                if let Some(service) = services.iter_mut().find(|sv| sv.id() == cf.service_id()) {
+                if known_service {
                     let mut latest_service = cf.service().clone();
                     // Note: Possibility of unintentional metric hits. Check Issue(#73).
                     match cf.state() {
@@ -160,8 +232,10 @@ impl Context for RootAuthThreescale {
                                     usages: vec![Usage {
                                         name: metric_name.unwrap_or_else(|| "Hits".into()),
                                         delta: proxy_rule.delta as i64,
+                                        plans: None,
                                     }],
                                     last: proxy_rule.last,
+                                    response_bytes_metric: None,
                                 })
                             }
                             rules_updated = true;
@@ -183,8 +257,10 @@ impl Context for RootAuthThreescale {
                                     usages: vec![Usage {
                                         name: metric_name.unwrap_or_else(|| "Hits".into()),
                                         delta: mapping_rule.delta as i64,
+                                        plans: None,
                                     }],
                                     last: mapping_rule.last,
+                                    response_bytes_metric: None,
                                 })
                             }
                             rules_updated = true;
@@ -192,7 +268,10 @@ impl Context for RootAuthThreescale {
                         _ => (),
                     }
                     if rules_updated {
-                        *service = latest_service;
+                        // swap the whole configuration so that contexts never
+                        // see a service with only part of its fetched config
+                        let config = self.configuration.as_mut().unwrap();
+                        Configuration::replace_service(config, latest_service);
                         cf.set_state(FetcherState::Inactive);
                     }
                 }
@@ -249,8 +328,6 @@ impl RootContext for RootAuthThreescale {
     }
 
     fn on_configure(&mut self, plugin_configuration_size: usize) -> bool {
-        use core::convert::TryFrom;
-
         info!(
             self,
             "on_configure: plugin_configuration_size is {}", plugin_configuration_size
@@ -274,23 +351,68 @@ impl RootContext for RootAuthThreescale {
 
         debug!(self, "loaded raw config");
 
-        let conf = match Configuration::try_from(conf.as_slice()) {
-            Ok(conf) => conf,
-            Err(e) => {
-                if let Ok(el) = ErrorLocation::try_from(&e) {
-                    let conf_str = String::from_utf8_lossy(conf.as_slice());
-                    for line in el.error_lines(conf_str.as_ref(), 4, 4) {
-                        error!(self, "{}", line);
+        let conf = match self.parse_configuration(conf.as_slice()) {
+            Some(parsed) => {
+                if let Err(e) =
+                    self.set_shared_data(LAST_GOOD_CONFIG_KEY, Some(conf.as_slice()), None)
+                {
+                    warn!(
+                        self,
+                        "failed to keep configuration as last known good: {:?}", e
+                    );
+                }
+                parsed
+            }
+            None => {
+                metrics::record_config_reload_failed(self);
+                if self.configuration.is_some() {
+                    warn!(
+                        self,
+                        "rejected configuration, keeping the one loaded previously"
+                    );
+                    return true;
+                }
+                match self.last_good_configuration() {
+                    Some(conf) => {
+                        warn!(
+                            self,
+                            "rejected configuration, using the last known good one"
+                        );
+                        conf
                     }
-                } else {
-                    // not a configuration syntax/data error (ie. programmatic)
-                    error!(self, "fatal configuration error: {:#?}", e);
+                    None => return false,
                 }
-                return false;
             }
         };
 
-        self.configuration = conf.into();
+        // Trace is the level set when starting up
+        let log_level = conf.get().log_level().unwrap_or(LogLevel::Trace);
+        crate::log::set_min_level(log_level);
+        proxy_wasm::set_log_level(log_level.into());
+
+        let environment = conf.get().environment();
+        if environment == Environment::Unknown {
+            warn!(
+                self,
+                "unknown environment configured, no service will be used"
+            );
+        }
+        for service in conf.get().services().into_iter().flatten() {
+            if service.environment() == Environment::Unknown {
+                warn!(
+                    self,
+                    "service {} has an unknown environment and will never be used",
+                    service.id()
+                );
+            }
+        }
+
+        // queues can't be unregistered, so it is kept if coalescing is turned off
+        if conf.get().coalesce_calls() {
+            coalesce::register_queue(self, self.id);
+        }
+
+        self.configuration = Some(Rc::new(conf));
         info!(
             self,
             "on_configure: plugin configuration {:#?}", self.configuration
@@ -308,24 +430,61 @@ impl RootContext for RootAuthThreescale {
         info!(self, "creating new context {}", context_id);
         let ctx = HttpAuthThreescale {
             context_id,
-            configuration: self.configuration.as_ref().unwrap().clone(),
+            configuration: Rc::clone(self.configuration.as_ref().unwrap()),
             id: self.rng.next_u32(),
             log_id: format!("{} ({}/http)", self.id, self.context_id),
+            shadow: Default::default(),
+            auth: None,
+            response_bytes: 0,
+            reports: Vec::new(),
+            plan: None,
+            cache_key: None,
+            backend_call: None,
+            coalesce_key: None,
+            decision: Default::default(),
+            backend_call_started: None,
+            rate_limits: Vec::new(),
+            body_limit: None,
+            request_body: None,
+            awaiting_verdict: false,
+            sanitize: None,
         };
 
         Some(ChildContext::HttpContext(Box::new(ctx)))
     }
 
+    fn on_queue_ready(&mut self, queue_id: u32) {
+        for release in coalesce::receive(self, queue_id) {
+            self.release_remote_waiters(&release);
+        }
+    }
+
     fn on_tick(&mut self) {
         debug!(self, "executing on_tick");
+        self.flush_batch();
+        let now = self
+            .get_current_time()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        for release in coalesce::expired(now) {
+            warn!(
+                self,
+                "coalesced call sent from another VM timed out, releasing its waiters"
+            );
+            self.release_remote_waiters(&release);
+        }
         if let Some(config) = self.get_configuration() {
             if let Some(sys) = self.get_system_config() {
                 let current_time = self.get_current_time();
                 if current_time < self.config_deadline {
-                    warn!(
-                        self,
-                        "on_tick running while the configuration is still valid"
-                    );
+                    // ticks are more frequent than configuration updates when
+                    // batching or coalescing calls
+                    if config.batching().is_none() && !config.coalesce_calls() {
+                        warn!(
+                            self,
+                            "on_tick running while the configuration is still valid"
+                        );
+                    }
                     return;
                 }
 
@@ -388,13 +547,80 @@ impl RootAuthThreescale {
     }
 
     pub fn set_next_tick(&mut self) -> Option<Duration> {
-        self.get_next_tick().map(|(tick, original_ttl)| {
+        let flush_interval = self
+            .get_configuration()
+            .and_then(|conf| conf.batching())
+            .map(|batching| Duration::from_secs(batching.flush_interval()));
+        let sweep = self
+            .get_configuration()
+            .filter(|conf| conf.coalesce_calls())
+            .map(|_| Duration::from_secs(COALESCE_SWEEP));
+        let tick = self.get_next_tick().map(|(tick, original_ttl)| {
             self.config_deadline = self
                 .get_current_time()
                 .checked_add(original_ttl)
                 .unwrap_or(std::time::UNIX_EPOCH);
-            self.set_tick_period(tick);
             tick
-        })
+        });
+        // batched usage is flushed and expired waits released on ticks, so
+        // they must be at least as frequent
+        let tick = [tick, flush_interval, sweep]
+            .iter()
+            .flatten()
+            .min()
+            .copied();
+        if let Some(tick) = tick {
+            self.set_tick_period(tick);
+        }
+        tick
+    }
+
+    // Responds to the requests of this VM waiting for a call sent from another one.
+    fn release_remote_waiters(&self, release: &coalesce::Release) {
+        let config = match self.get_configuration() {
+            Some(config) => config,
+            None => return,
+        };
+        let service = release
+            .service_id
+            .as_deref()
+            .and_then(|id| config.get_service(id));
+        // the response of the call is not sent along with its verdict
+        let verdict = Verdict {
+            outcome: release.outcome,
+            headers: &[],
+            status: None,
+            plan: None,
+            token_id: 0,
+            reason: release.reason.as_deref(),
+        };
+        let waiters = coalesce::complete(release.key.as_str());
+        if !waiters.is_empty() {
+            debug!(
+                self,
+                "releasing {} requests coalesced with a call from another VM ({:?})",
+                waiters.len(),
+                release.outcome
+            );
+        }
+        coalesce::for_each_waiter(self, waiters.as_slice(), self.context_id, |waiter| {
+            metrics::record(self, release.outcome, service.map(Service::id));
+            coalesce::settle(waiter, verdict::respond(self, config, &verdict, service));
+        });
+    }
+
+    // ticks can be more frequent than the flush interval when coalescing calls
+    fn flush_batch(&mut self) {
+        let now = self.get_current_time();
+        let flush_interval = match self.get_configuration().and_then(|conf| conf.batching()) {
+            Some(batching) if now >= self.flush_deadline => batching.flush_interval(),
+            _ => return,
+        };
+        self.flush_deadline = now
+            .checked_add(Duration::from_secs(flush_interval))
+            .unwrap_or(now);
+        if let Some(config) = self.get_configuration() {
+            batch::flush(self, config);
+        }
     }
 }
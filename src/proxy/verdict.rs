@@ -0,0 +1,233 @@
+// Responses to requests once the verdict of their authorization is known.
+//
+// Verdicts are applied on the stream of the current effective context, which
+// might be waiting for a call sent by another context or, with calls sent from
+// other worker VMs, be released by the root context. So nothing here touches
+// the state of a context: the changes to it are returned to be settled by the
+// context itself.
+use proxy_wasm::hostcalls;
+
+use super::coalesce::Settlement;
+use super::metrics::{self, Outcome};
+use crate::configuration::api::v1::Configuration;
+use crate::configuration::{OnBackendError, Response};
+use crate::log::IdentLogger;
+use crate::threescale::{response, NoMatchBehavior, Service};
+
+static DEFAULT_DENY_RESPONSE: Response = Response {
+    status: None,
+    headers: None,
+    body: None,
+};
+
+// Why a request is denied, to pick the response sent to the client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DenyReason {
+    Rejected,
+    CredentialsMissing,
+    BackendUnreachable,
+    NoMatch,
+}
+
+impl DenyReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Rejected => "rejected",
+            Self::CredentialsMissing => "credentials_missing",
+            Self::BackendUnreachable => "backend_unreachable",
+            Self::NoMatch => "no_match",
+        }
+    }
+}
+
+// The outcome of a call to the backend, along with its response.
+pub struct Verdict<'a> {
+    pub outcome: Outcome,
+    pub headers: &'a [(String, String)],
+    pub status: Option<&'a str>,
+    pub plan: Option<&'a str>,
+    pub token_id: u32,
+    // why 3scale rejected the request
+    pub reason: Option<&'a str>,
+}
+
+// only infrastructure failures fail open, never actual denials from 3scale
+pub fn fails_open(config: &Configuration, service: Option<&Service>) -> bool {
+    let on_backend_error = service
+        .and_then(Service::failure_policy)
+        .and_then(|policy| policy.on_backend_error())
+        .unwrap_or_else(|| config.on_backend_error());
+    on_backend_error == OnBackendError::Allow
+}
+
+// the failure policy of the service takes precedence over the top level responses
+fn deny_response<'a>(
+    config: &'a Configuration,
+    reason: DenyReason,
+    service: Option<&'a Service>,
+) -> &'a Response {
+    let policy = service.and_then(Service::failure_policy);
+    let response = match reason {
+        DenyReason::Rejected | DenyReason::NoMatch => None,
+        DenyReason::CredentialsMissing => policy.and_then(|policy| policy.credentials_missing()),
+        DenyReason::BackendUnreachable => policy.and_then(|policy| policy.backend_error()),
+    };
+    if let Some(response) = response {
+        return response;
+    }
+
+    match (config.deny_response(), reason) {
+        (Some(deny), DenyReason::BackendUnreachable) => deny.backend_unreachable(),
+        (Some(deny), _) => deny.response(),
+        (None, _) => &DEFAULT_DENY_RESPONSE,
+    }
+}
+
+fn send(ctx: &dyn IdentLogger, status: u32, headers: Vec<(&str, &str)>, body: &str) {
+    if let Err(e) = hostcalls::send_http_response(status, headers, Some(body.as_bytes())) {
+        error!(ctx, "failed to send {} response: {:?}", status, e);
+    }
+}
+
+fn resume(ctx: &dyn IdentLogger) {
+    if let Err(e) = hostcalls::resume_http_request() {
+        error!(ctx, "failed to resume request: {:?}", e);
+    }
+}
+
+// Each of the deny responses returns the reason to record the denial with.
+pub fn send_deny_response(
+    ctx: &dyn IdentLogger,
+    config: &Configuration,
+    reason: DenyReason,
+    service: Option<&Service>,
+) -> String {
+    metrics::record_denial(ctx, reason.as_str(), service.map(Service::id));
+    let response = deny_response(config, reason, service);
+    let status = match (reason, service.and_then(Service::no_match_behavior)) {
+        (
+            DenyReason::NoMatch,
+            Some(NoMatchBehavior::Deny {
+                status: Some(status),
+            }),
+        ) => *status,
+        _ => response.status(),
+    };
+    send(ctx, status, response.headers(), response.body());
+    debug!(ctx, "{} sent ({:?})", status, reason);
+    reason.as_str().to_string()
+}
+
+// The deny response, with a status fitting the reason unless one is configured.
+pub fn send_rejection_response(
+    ctx: &dyn IdentLogger,
+    config: &Configuration,
+    reason: Option<&str>,
+    service: Option<&Service>,
+) -> String {
+    metrics::record_denial(ctx, DenyReason::Rejected.as_str(), service.map(Service::id));
+    let response = deny_response(config, DenyReason::Rejected, service);
+    let status = response
+        .status
+        .or_else(|| reason.map(response::rejection_status))
+        .unwrap_or_else(|| response.status());
+    let mut headers = response.headers();
+    if let (Some(name), Some(reason)) = (config.rejection_reason_header(), reason) {
+        headers.push((name, reason));
+    }
+    send(ctx, status, headers, response.body());
+    debug!(ctx, "{} sent (rejected: {:?})", status, reason);
+    match reason {
+        Some(reason) => format!("{}: {}", DenyReason::Rejected.as_str(), reason),
+        None => DenyReason::Rejected.as_str().to_string(),
+    }
+}
+
+// The configured deny response with a 429 status and the rate limiting headers.
+pub fn send_limits_exceeded_response(
+    ctx: &dyn IdentLogger,
+    config: &Configuration,
+    rate_headers: &[(String, String)],
+    service: Option<&Service>,
+) -> String {
+    let (response, status) = service
+        .and_then(Service::failure_policy)
+        .and_then(|policy| policy.limits_exceeded())
+        .unwrap_or_else(|| (deny_response(config, DenyReason::Rejected, service), 429));
+    let mut headers = response.headers();
+    headers.extend(rate_headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    if let Some(name) = config.rejection_reason_header() {
+        headers.push((name, "limits_exceeded"));
+    }
+    metrics::record_denial(ctx, "limits_exceeded", service.map(Service::id));
+    send(ctx, status, headers, response.body());
+    debug!(ctx, "{} sent (limits exceeded)", status);
+    "limits_exceeded".to_string()
+}
+
+// Resumes or denies the request of the current effective context, returning
+// the changes to the state of its context.
+pub fn respond(
+    ctx: &dyn IdentLogger,
+    config: &Configuration,
+    verdict: &Verdict,
+    service: Option<&Service>,
+) -> Settlement {
+    let Verdict {
+        outcome,
+        headers,
+        status,
+        plan,
+        token_id,
+        reason,
+    } = *verdict;
+    let mut settlement = Settlement::default();
+    match outcome {
+        Outcome::Authorized => {
+            info!(ctx, "on_http_call_response: authorized {}", token_id);
+            settlement.authorized = true;
+            settlement.plan = plan.map(str::to_string);
+            settlement.rate_limits = response::standard_rate_limit_headers(headers);
+            resume(ctx);
+        }
+        Outcome::BackendError => {
+            info!(
+                ctx,
+                "on_http_call_response: backend unavailable {} (status {:?})", token_id, status
+            );
+            if fails_open(config, service) {
+                warn!(
+                    ctx,
+                    "on_http_call_response: backend unreachable, allowing request {}", token_id
+                );
+                settlement.unvalidated = true;
+                resume(ctx);
+            } else {
+                settlement.denial = Some(send_deny_response(
+                    ctx,
+                    config,
+                    DenyReason::BackendUnreachable,
+                    service,
+                ));
+            }
+        }
+        Outcome::LimitsExceeded => {
+            info!(ctx, "on_http_call_response: limits exceeded {}", token_id);
+            let rate_headers = response::rate_limit_headers(headers);
+            settlement.denial = Some(send_limits_exceeded_response(
+                ctx,
+                config,
+                rate_headers.as_slice(),
+                service,
+            ));
+        }
+        _ => {
+            info!(
+                ctx,
+                "on_http_call_response: forbidden {} (reason {:?})", token_id, reason
+            );
+            settlement.denial = Some(send_rejection_response(ctx, config, reason, service));
+        }
+    }
+    settlement
+}
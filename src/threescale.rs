@@ -1,6 +1,8 @@
 mod backend;
 mod credentials;
 mod mapping_rule;
+pub mod response;
+pub mod response_rule;
 mod service;
 mod system;
 mod usage;
@@ -8,6 +10,7 @@ mod usage;
 pub use backend::Backend;
 pub use credentials::{Credentials, Error as CredentialsError};
 pub use mapping_rule::MappingRule;
-pub use service::{Environment, Service};
+pub use response_rule::ResponseRule;
+pub use service::{Environment, NoMatchBehavior, ParamPassthrough, Sanitize, Service, UsageMerge};
 pub use system::System;
 pub use usage::Usage;
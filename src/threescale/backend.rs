@@ -12,7 +12,6 @@ pub struct Backend {
 }
 
 impl Backend {
-    #[allow(dead_code)]
     pub fn name(&self) -> Option<&str> {
         self.name.as_deref()
     }
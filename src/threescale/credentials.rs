@@ -18,6 +18,7 @@ pub struct Credentials {
     user_key: Option<Vec<Source>>,
     app_id: Option<Vec<Source>>,
     app_key: Option<Vec<Source>>,
+    max_length: Option<usize>,
 }
 
 impl Credentials {
@@ -31,6 +32,7 @@ impl Credentials {
             user_key,
             app_id,
             app_key,
+            max_length: None,
         }
     }
 
@@ -46,12 +48,49 @@ impl Credentials {
         self.app_key.as_ref()
     }
 
+    pub fn max_length(&self) -> Option<usize> {
+        self.max_length
+    }
+
+    // bytes of the request body to buffer for looking up credentials, if any
+    pub fn body_limit(&self) -> Option<usize> {
+        [self.user_key(), self.app_id(), self.app_key()]
+            .iter()
+            .flatten()
+            .flat_map(|sources| sources.iter())
+            .filter_map(Source::body_limit)
+            .max()
+    }
+
+    fn exceeds_max_length(&self, value: &str) -> bool {
+        self.max_length()
+            .map_or(false, |max_length| value.len() > max_length)
+    }
+
+    // Values longer than the maximum length are discarded as if they were not found.
+    fn check_length(&self, ctx: &HttpAuthThreescale, name: &str, value: String) -> Option<String> {
+        match self.max_length() {
+            Some(max_length) if self.exceeds_max_length(value.as_str()) => {
+                warn!(
+                    ctx,
+                    "discarding {} credential of {} bytes, longer than the maximum of {}",
+                    name,
+                    value.len(),
+                    max_length
+                );
+                None
+            }
+            _ => Some(value),
+        }
+    }
+
+    // Resolves the applications along with the source each one was found in.
     pub fn resolve(
         &self,
         ctx: &HttpAuthThreescale,
         rh: &RequestHeaders,
         url: &url::Url,
-    ) -> Result<Vec<Application>, Error> {
+    ) -> Result<Vec<(Application, &Source)>, Error> {
         let mut apps = vec![];
 
         let user_key = self
@@ -59,25 +98,30 @@ impl Credentials {
             .and_then(|sources| {
                 sources
                     .iter()
-                    .find_map(|source| source.resolve(ctx, rh, url))
-                    .and_then(|values| values.get(0).map(|val| val.as_ref().to_string()))
+                    .find_map(|source| source.resolve(ctx, rh, url).map(|values| (values, source)))
+            })
+            .and_then(|(values, source)| {
+                values.get(0).map(|val| (val.as_ref().to_string(), source))
             })
-            .map(|user_key| Application::UserKey(user_key.into()));
+            .and_then(|(user_key, source)| {
+                self.check_length(ctx, "user_key", user_key)
+                    .map(|user_key| (user_key, source))
+            })
+            .map(|(user_key, source)| (Application::UserKey(user_key.into()), source));
 
         let app_values = self.app_id().and_then(|sources| {
-            sources
-                .iter()
-                .find_map(|source| source.resolve(ctx, rh, url))
-                .map(|values| {
+            sources.iter().find_map(|source| {
+                source.resolve(ctx, rh, url).map(|values| {
                     let app_id = values.get(0).map(|val| val.as_ref().to_string());
                     let app_key = values.get(1).map(|val| val.as_ref().to_string());
-                    (app_id, app_key)
+                    (app_id, app_key, source)
                 })
+            })
         });
 
-        let (app_id, app_key) = match app_values {
-            Some((id, key)) => (id, key),
-            _ => (None, None),
+        let (app_id, app_key, app_source) = match app_values {
+            Some((id, key, source)) => (id, key, Some(source)),
+            _ => (None, None, None),
         };
 
         let app_key = app_key.or_else(|| {
@@ -89,7 +133,22 @@ impl Credentials {
             })
         });
 
-        let app_id = app_id.map(|id| Application::AppId(id.into(), app_key.map(|k| k.into())));
+        // an invalid app_key invalidates the whole application
+        let app_id = app_id.and_then(|id| self.check_length(ctx, "app_id", id));
+        let (app_id, app_key) = match app_key {
+            Some(key) => match self.check_length(ctx, "app_key", key) {
+                Some(key) => (app_id, Some(key)),
+                None => (None, None),
+            },
+            None => (app_id, None),
+        };
+
+        let app_id = app_id.zip(app_source).map(|(id, source)| {
+            (
+                Application::AppId(id.into(), app_key.map(|k| k.into())),
+                source,
+            )
+        });
 
         if let Some(uk) = user_key {
             apps.push(uk);
@@ -115,6 +174,7 @@ impl<'de> Deserialize<'de> for Credentials {
             UserKey,
             AppId,
             AppKey,
+            MaxLength,
         }
 
         struct CredentialsVisitor;
@@ -132,6 +192,7 @@ impl<'de> Deserialize<'de> for Credentials {
                 let mut user_key = None;
                 let mut app_id = None;
                 let mut app_key = None;
+                let mut max_length = None;
 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -153,6 +214,12 @@ impl<'de> Deserialize<'de> for Credentials {
                             }
                             app_key = Some(map.next_value()?);
                         }
+                        Field::MaxLength => {
+                            if max_length.is_some() {
+                                return Err(de::Error::duplicate_field("max_length"));
+                            }
+                            max_length = Some(map.next_value()?);
+                        }
                     }
                 }
                 if let (None, None) = (&user_key, &app_id) {
@@ -165,6 +232,7 @@ impl<'de> Deserialize<'de> for Credentials {
                     user_key,
                     app_id,
                     app_key,
+                    max_length,
                 };
 
                 Ok(credentials)
@@ -173,7 +241,7 @@ impl<'de> Deserialize<'de> for Credentials {
 
         deserializer.deserialize_struct(
             "Credentials",
-            &["user_key", "app_id", "app_key"],
+            &["user_key", "app_id", "app_key", "max_length"],
             CredentialsVisitor,
         )
     }
@@ -211,7 +279,8 @@ impl Serialize for Credentials {
             actions
                 .iter()
                 .filter(|&a| matches!(a, FieldAction::Serialize(_, _)))
-                .count(),
+                .count()
+                + self.max_length().map_or(0, |_| 1),
         )?;
 
         for f in &actions {
@@ -220,6 +289,35 @@ impl Serialize for Credentials {
                 FieldAction::Skip(key) => state.skip_field(key)?,
             }
         }
+        match self.max_length() {
+            Some(max_length) => state.serialize_field("max_length", &max_length)?,
+            None => state.skip_field("max_length")?,
+        }
         state.end()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn discards_values_over_the_max_length() {
+        let credentials: Credentials = serde_json::from_str(
+            r#"{ "user_key": [{ "header": { "keys": ["x-key"] } }], "max_length": 8 }"#,
+        )
+        .unwrap();
+
+        assert_eq!(credentials.max_length(), Some(8));
+        assert!(!credentials.exceeds_max_length("1234567"));
+        assert!(!credentials.exceeds_max_length("12345678"));
+        assert!(credentials.exceeds_max_length("123456789"));
+    }
+
+    #[test]
+    fn keeps_values_of_any_length_without_a_max_length() {
+        let credentials = Credentials::new(None, None, None);
+
+        assert!(!credentials.exceeds_max_length(&"k".repeat(64 * 1024)));
+    }
+}
@@ -1,15 +1,48 @@
+use std::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
 use threescalers::http::mapping_rule::{Method, RestRule};
 
 use super::Usage;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "MappingRuleDef")]
 pub struct MappingRule {
     #[serde(flatten)]
     pub rule: RestRule,
     pub usages: Vec<Usage>,
     #[serde(default)]
     pub last: bool,
+    // metric to report the size in bytes of the response body with
+    pub response_bytes_metric: Option<String>,
+}
+
+// Deserialization helper to default the method to match any of them.
+#[derive(Debug, Clone, Deserialize)]
+struct MappingRuleDef {
+    method: Option<String>,
+    pattern: String,
+    usages: Vec<Usage>,
+    #[serde(default)]
+    last: bool,
+    response_bytes_metric: Option<String>,
+}
+
+impl TryFrom<MappingRuleDef> for MappingRule {
+    type Error = String;
+
+    fn try_from(def: MappingRuleDef) -> Result<Self, Self::Error> {
+        let method = Method::from(def.method.as_deref().unwrap_or("any"));
+        let rule = RestRule::new(method, def.pattern.as_str())
+            .map_err(|e| format!("invalid mapping rule pattern {}: {:?}", def.pattern, e))?;
+
+        Ok(Self {
+            rule,
+            usages: def.usages,
+            last: def.last,
+            response_bytes_metric: def.response_bytes_metric,
+        })
+    }
 }
 
 impl MappingRule {
@@ -18,7 +51,6 @@ impl MappingRule {
         self.rule.method()
     }
 
-    #[allow(dead_code)]
     pub fn pattern(&self) -> String {
         self.rule.pattern()
     }
@@ -44,4 +76,24 @@ impl MappingRule {
     pub fn is_last(&self) -> bool {
         self.last
     }
+
+    pub fn response_bytes_metric(&self) -> Option<&str> {
+        self.response_bytes_metric.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn method_defaults_to_any() {
+        let rule: MappingRule = serde_json::from_str(
+            r#"{ "pattern": "/items/{id}", "usages": [{ "name": "Hits", "delta": 1 }] }"#,
+        )
+        .unwrap();
+
+        assert!(rule.is_match(&Method::GET, "/items/42"));
+        assert!(rule.is_match(&Method::POST, "/items/42"));
+    }
 }
@@ -0,0 +1,278 @@
+// Data extracted from the XML bodies of 3scale backend responses.
+//
+// Responses are small and have a well known shape, so we look up the few
+// elements we are interested in rather than fully parsing the document.
+use std::borrow::Cow;
+
+// Returns the text content of the first element with the given name.
+fn element<'b>(body: &'b str, name: &str) -> Option<&'b str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = body.find(open.as_str())? + open.len();
+    let len = body[start..].find(close.as_str())?;
+    Some(&body[start..start + len])
+}
+
+fn unescape(text: &str) -> Cow<str> {
+    if !text.contains('&') {
+        return Cow::from(text);
+    }
+
+    // &amp; goes last so that it does not produce new entities
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    Cow::from(text)
+}
+
+// Name of the plan of the application, as returned by authorizations.
+pub fn plan(body: &str) -> Option<Cow<str>> {
+    element(body, "plan")
+        .map(str::trim)
+        .filter(|plan| !plan.is_empty())
+        .map(unescape)
+}
+
+// Reason given by 3scale when usage limits are exceeded.
+const LIMITS_EXCEEDED: &str = "limits_exceeded";
+
+// Whether a rejection is due to exceeded usage limits, preferably as told
+// by the rejection reason header, or otherwise by the reason in the body.
+pub fn limits_exceeded(reason_header: Option<&str>, body: Option<&str>) -> bool {
+    match reason_header {
+        Some(reason) => reason.trim() == LIMITS_EXCEEDED,
+        None => body
+            .and_then(|body| element(body, "reason"))
+            .map_or(false, |reason| reason.contains("limits are exceeded")),
+    }
+}
+
+// Reason of a rejection, preferably as told by the rejection reason header,
+// or otherwise by the error code or the reason in the body.
+pub fn rejection_reason<'a>(
+    reason_header: Option<&'a str>,
+    body: Option<&'a str>,
+) -> Option<Cow<'a, str>> {
+    if let Some(reason) = reason_header.map(str::trim).filter(|r| !r.is_empty()) {
+        return Some(Cow::from(reason));
+    }
+
+    let body = body?;
+    let code = body.find("<error code=\"").and_then(|start| {
+        let code = &body[start + "<error code=\"".len()..];
+        code.find('"').map(|end| &code[..end])
+    });
+    code.or_else(|| element(body, "reason"))
+        .map(str::trim)
+        .filter(|reason| !reason.is_empty())
+        .map(unescape)
+}
+
+// Status for clients of a rejection: credentials 3scale does not accept are
+// unauthorized, and exceeded limits too many requests.
+pub fn rejection_status(reason: &str) -> u32 {
+    match reason {
+        LIMITS_EXCEEDED => 429,
+        "application_not_found"
+        | "application_key_invalid"
+        | "user_key_invalid"
+        | "access_token_invalid"
+        | "access_token_storage_error" => 401,
+        _ => 403,
+    }
+}
+
+// Rate limiting headers for clients, from those in a 3scale response.
+pub fn rate_limit_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let mut rate_headers = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.to_ascii_lowercase();
+            let name = match name.as_str() {
+                "3scale-limit-remaining" => "x-ratelimit-remaining".to_string(),
+                "3scale-limit-max-value" => "x-ratelimit-limit".to_string(),
+                "3scale-limit-reset" => "x-ratelimit-reset".to_string(),
+                "retry-after" => name,
+                _ if name.starts_with("x-ratelimit-") => name,
+                _ => return None,
+            };
+            Some((name, value.clone()))
+        })
+        .collect::<Vec<_>>();
+
+    let has_retry_after = rate_headers.iter().any(|(name, _)| name == "retry-after");
+    if !has_retry_after {
+        let reset = rate_headers
+            .iter()
+            .find(|(name, _)| name == "x-ratelimit-reset")
+            .map(|(_, value)| value.clone());
+        if let Some(reset) = reset {
+            rate_headers.push(("retry-after".to_string(), reset));
+        }
+    }
+
+    rate_headers
+}
+
+// Standard RateLimit headers for clients of authorized requests, from the
+// limit headers in a 3scale response.
+pub fn standard_rate_limit_headers(headers: &[(String, String)]) -> Vec<(&'static str, String)> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = match name.to_ascii_lowercase().as_str() {
+                "3scale-limit-max-value" => "ratelimit-limit",
+                "3scale-limit-remaining" => "ratelimit-remaining",
+                "3scale-limit-reset" => "ratelimit-reset",
+                _ => return None,
+            };
+            Some((name, value.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const AUTHORIZED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<status>
+  <authorized>true</authorized>
+  <plan>Gold &amp; Silver</plan>
+  <usage_reports>
+    <usage_report metric="hits" period="minute">
+      <period_start>2021-02-01 10:00:00 +0000</period_start>
+      <period_end>2021-02-01 10:01:00 +0000</period_end>
+      <max_value>100</max_value>
+      <current_value>10</current_value>
+    </usage_report>
+  </usage_reports>
+</status>"#;
+
+    const LIMITED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<status>
+  <authorized>false</authorized>
+  <reason>usage limits are exceeded</reason>
+  <plan>Basic</plan>
+</status>"#;
+
+    #[test]
+    fn finds_rejection_reasons() {
+        const NOT_FOUND: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<error code="application_not_found">application with id="a" was not found</error>"#;
+
+        assert_eq!(
+            rejection_reason(Some(" user_key_invalid "), Some(LIMITED)).as_deref(),
+            Some("user_key_invalid")
+        );
+        assert_eq!(
+            rejection_reason(None, Some(NOT_FOUND)).as_deref(),
+            Some("application_not_found")
+        );
+        assert_eq!(
+            rejection_reason(None, Some(LIMITED)).as_deref(),
+            Some("usage limits are exceeded")
+        );
+        assert_eq!(rejection_reason(None, Some("")), None);
+        assert_eq!(rejection_status("application_not_found"), 401);
+        assert_eq!(rejection_status("limits_exceeded"), 429);
+        assert_eq!(rejection_status("application_not_active"), 403);
+    }
+
+    #[test]
+    fn detects_exceeded_limits() {
+        assert!(limits_exceeded(Some("limits_exceeded"), None));
+        assert!(!limits_exceeded(
+            Some("application_not_found"),
+            Some(LIMITED)
+        ));
+        assert!(limits_exceeded(None, Some(LIMITED)));
+        assert!(!limits_exceeded(
+            None,
+            Some("<status><reason>application key is invalid</reason></status>")
+        ));
+        assert!(!limits_exceeded(None, Some("")));
+        assert!(!limits_exceeded(None, None));
+    }
+
+    #[test]
+    fn translates_rate_limit_headers() {
+        let headers = [
+            (":status", "409"),
+            ("3scale-Limit-Remaining", "0"),
+            ("3scale-limit-reset", "42"),
+            ("3scale-limit-max-value", "100"),
+            ("x-ratelimit-policy", "100;w=60"),
+            ("content-type", "application/xml"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<_>>();
+
+        let expected = [
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "42"),
+            ("x-ratelimit-limit", "100"),
+            ("x-ratelimit-policy", "100;w=60"),
+            ("retry-after", "42"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<_>>();
+
+        assert_eq!(rate_limit_headers(headers.as_slice()), expected);
+    }
+
+    #[test]
+    fn translates_standard_rate_limit_headers() {
+        let headers = [
+            (":status", "200"),
+            ("3scale-limit-max-value", "100"),
+            ("3scale-Limit-Remaining", "90"),
+            ("3scale-limit-reset", "42"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            standard_rate_limit_headers(headers.as_slice()),
+            vec![
+                ("ratelimit-limit", "100".to_string()),
+                ("ratelimit-remaining", "90".to_string()),
+                ("ratelimit-reset", "42".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_retry_after_from_the_backend() {
+        let headers = vec![
+            ("Retry-After".to_string(), "10".to_string()),
+            ("3scale-limit-reset".to_string(), "42".to_string()),
+        ];
+
+        assert_eq!(
+            rate_limit_headers(headers.as_slice()),
+            vec![
+                ("retry-after".to_string(), "10".to_string()),
+                ("x-ratelimit-reset".to_string(), "42".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn extracts_the_plan() {
+        assert_eq!(plan(AUTHORIZED).as_deref(), Some("Gold & Silver"));
+    }
+
+    #[test]
+    fn ignores_missing_or_empty_plans() {
+        assert_eq!(plan("<status><authorized>true</authorized></status>"), None);
+        assert_eq!(plan("<status><plan></plan></status>"), None);
+        assert_eq!(plan("not xml"), None);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_DELTA: i64 = 1;
+
+#[derive(Debug, thiserror::Error)]
+#[error("status pattern {0} is not three digits or x, ie. 404 or 5xx")]
+pub struct StatusPatternError(String);
+
+// A response status, where an "x" matches any digit, ie. "5xx".
+#[repr(transparent)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct StatusPattern(String);
+
+impl TryFrom<String> for StatusPattern {
+    type Error = StatusPatternError;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        let pattern = pattern.to_ascii_lowercase();
+        if pattern.len() == 3 && pattern.bytes().all(|b| b == b'x' || b.is_ascii_digit()) {
+            Ok(Self(pattern))
+        } else {
+            Err(StatusPatternError(pattern))
+        }
+    }
+}
+
+impl From<StatusPattern> for String {
+    fn from(pattern: StatusPattern) -> Self {
+        pattern.0
+    }
+}
+
+impl StatusPattern {
+    pub fn matches(&self, status: &str) -> bool {
+        status.len() == 3
+            && self
+                .0
+                .bytes()
+                .zip(status.bytes())
+                .all(|(p, s)| p == b'x' || p == s)
+    }
+}
+
+// A response header, either just present or with the given value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeaderMatch {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl HeaderMatch {
+    pub fn matches(&self, headers: &[(String, String)]) -> bool {
+        headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case(self.name.as_str())
+                && self.value.as_ref().map_or(true, |v| v == value)
+        })
+    }
+}
+
+// Usage reported for responses matching both the status and the header, if set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponseRule {
+    pub status: Option<StatusPattern>,
+    pub header: Option<HeaderMatch>,
+    pub metric: String,
+    pub delta: Option<i64>,
+}
+
+impl ResponseRule {
+    pub fn matches(&self, status: &str, headers: &[(String, String)]) -> bool {
+        self.status
+            .as_ref()
+            .map_or(true, |pattern| pattern.matches(status))
+            && self
+                .header
+                .as_ref()
+                .map_or(true, |header| header.matches(headers))
+    }
+
+    pub fn delta(&self) -> i64 {
+        self.delta.unwrap_or(DEFAULT_DELTA)
+    }
+}
+
+// Usage of the rules matching the response, adding up the deltas of each metric.
+pub fn usage(
+    rules: &[ResponseRule],
+    status: &str,
+    headers: &[(String, String)],
+) -> Vec<(String, String)> {
+    let mut usage = BTreeMap::new();
+    for rule in rules.iter().filter(|rule| rule.matches(status, headers)) {
+        *usage.entry(rule.metric.as_str()).or_insert(0) += rule.delta();
+    }
+    usage
+        .into_iter()
+        .map(|(metric, delta)| (metric.to_string(), delta.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adds_up_usage_of_matching_rules() {
+        let rules: Vec<ResponseRule> = serde_json::from_str(
+            r#"[
+              { "status": "5XX", "metric": "errors" },
+              { "status": "503", "metric": "errors", "delta": 2 },
+              { "header": { "name": "x-cache", "value": "hit" }, "metric": "cached" },
+              { "status": "2xx", "header": { "name": "x-paid" }, "metric": "paid" }
+            ]"#,
+        )
+        .unwrap();
+        let headers = vec![("X-Cache".to_string(), "hit".to_string())];
+
+        assert_eq!(
+            usage(rules.as_slice(), "503", headers.as_slice()),
+            vec![
+                ("cached".to_string(), "1".to_string()),
+                ("errors".to_string(), "3".to_string())
+            ]
+        );
+        assert!(usage(rules.as_slice(), "200", &[]).is_empty());
+        assert!(serde_json::from_str::<StatusPattern>(r#""5x""#).is_err());
+    }
+}
@@ -1,9 +1,14 @@
+use std::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
+use threescalers::api_call::Kind;
 
-use super::{Credentials, MappingRule};
-use crate::util::glob::GlobPatternSet;
+use super::{Backend, Credentials, MappingRule, ResponseRule};
+use crate::configuration::{FailurePolicy, RetryPolicy, Source};
+use crate::upstream::Upstream;
+use crate::util::glob::{GlobPatternSet, Specificity};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Environment {
     Production,
@@ -30,6 +35,225 @@ impl Default for Environment {
     }
 }
 
+// How to combine the deltas of a metric hit by more than one matching rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageMerge {
+    Sum,
+    Max,
+    Last,
+}
+
+impl Default for UsageMerge {
+    fn default() -> Self {
+        Self::Sum
+    }
+}
+
+// What to do with requests matching no mapping rule
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum NoMatchBehavior {
+    // the status defaults to the one of the deny response
+    Deny { status: Option<u32> },
+    // let through without authorizing nor reporting them
+    AllowWithoutReport,
+    ReportDefaultMetric { metric: String },
+}
+
+impl Default for NoMatchBehavior {
+    fn default() -> Self {
+        Self::Deny { status: None }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("path {0} is not absolute")]
+pub struct PathError(String);
+
+#[repr(transparent)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AbsolutePath(String);
+
+impl TryFrom<String> for AbsolutePath {
+    type Error = PathError;
+
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        if path.starts_with('/') {
+            Ok(Self(path))
+        } else {
+            Err(PathError(path))
+        }
+    }
+}
+
+impl From<AbsolutePath> for String {
+    fn from(path: AbsolutePath) -> Self {
+        path.0
+    }
+}
+
+impl AbsolutePath {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+// Paths in the backend overriding the default endpoints for each kind of call.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackendPaths {
+    pub authrep: Option<AbsolutePath>,
+    pub authorize: Option<AbsolutePath>,
+    pub report: Option<AbsolutePath>,
+}
+
+impl BackendPaths {
+    pub fn path(&self, kind: &Kind) -> Option<&str> {
+        let path = match kind {
+            Kind::AuthRep => self.authrep.as_ref(),
+            Kind::Authorize => self.authorize.as_ref(),
+            Kind::Report => self.report.as_ref(),
+        };
+        path.map(AbsolutePath::as_str)
+    }
+}
+
+// Secret shared with the upstream to sign authorized requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Signature {
+    pub secret: String,
+}
+
+impl Signature {
+    pub fn secret(&self) -> &str {
+        self.secret.as_str()
+    }
+}
+
+// A query string parameter from the client request to be passed on to 3scale.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParamPassthrough {
+    pub query_string: String,
+    // name of the parameter in the call to 3scale, defaults to `query_string`
+    pub name: Option<String>,
+}
+
+impl ParamPassthrough {
+    pub fn query_string(&self) -> &str {
+        self.query_string.as_str()
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.query_string.as_str())
+    }
+}
+
+// Requests matching any of the paths and methods, if set, skip authorization.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BypassRule {
+    pub paths: GlobPatternSet,
+    pub methods: Option<Vec<String>>,
+}
+
+impl BypassRule {
+    pub fn is_match(&self, method: &str, path: &str) -> bool {
+        self.methods.as_ref().map_or(true, |methods| {
+            methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+        }) && self.paths.is_match(path)
+    }
+}
+
+// Requests allowed per client IP address over a period, counted locally.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientIpLimit {
+    pub requests: u64,
+    // seconds
+    pub period: u64,
+    // header with the client address as its first entry, such as x-forwarded-for,
+    // instead of the address of the peer
+    pub header: Option<String>,
+}
+
+impl ClientIpLimit {
+    pub fn header(&self) -> Option<&str> {
+        self.header.as_deref()
+    }
+
+    // The address of the client in a header value or a peer address, without any port.
+    pub fn client_ip<'a>(&self, address: &'a str) -> Option<&'a str> {
+        let address = if self.header.is_some() {
+            address.split(',').next()?.trim()
+        } else {
+            address.trim()
+        };
+        let ip = if let Some(bracketed) = address.strip_prefix('[') {
+            bracketed.split(']').next()?
+        } else if address.matches(':').count() == 1 {
+            address.split(':').next()?
+        } else {
+            address
+        };
+        Some(ip).filter(|ip| !ip.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bypass {
+    pub rules: Vec<BypassRule>,
+    // app id the usage of bypassing requests is reported under, if any
+    pub anonymous_app: Option<String>,
+    pub client_ip_limit: Option<ClientIpLimit>,
+}
+
+impl Bypass {
+    pub fn is_match(&self, method: &str, path: &str) -> bool {
+        self.rules.iter().any(|rule| rule.is_match(method, path))
+    }
+
+    pub fn anonymous_app(&self) -> Option<&str> {
+        self.anonymous_app.as_deref()
+    }
+
+    pub const fn client_ip_limit(&self) -> Option<&ClientIpLimit> {
+        self.client_ip_limit.as_ref()
+    }
+}
+
+// Removal of 3scale headers that clients could preset to spoof the information
+// passed to the upstream, or that could leak back to them in responses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sanitize {
+    // strip x-3scale-* headers from requests before looking up credentials
+    pub request_headers: Option<bool>,
+    // strip x-3scale-* and Powered-By headers from responses
+    pub response_headers: Option<bool>,
+}
+
+const THREESCALE_HEADER_PREFIX: &str = "x-3scale-";
+
+fn is_threescale_header(name: &str) -> bool {
+    name.get(..THREESCALE_HEADER_PREFIX.len())
+        .map_or(false, |prefix| {
+            prefix.eq_ignore_ascii_case(THREESCALE_HEADER_PREFIX)
+        })
+}
+
+impl Sanitize {
+    pub fn response_headers(&self) -> bool {
+        self.response_headers.unwrap_or(false)
+    }
+
+    pub fn strips_request_header(&self, name: &str) -> bool {
+        self.request_headers.unwrap_or(false) && is_threescale_header(name)
+    }
+
+    pub fn strips_response_header(&self, name: &str) -> bool {
+        self.response_headers()
+            && (is_threescale_header(name) || name.eq_ignore_ascii_case("powered-by"))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Service {
     pub id: String,
@@ -40,6 +264,39 @@ pub struct Service {
     pub authorities: GlobPatternSet,
     pub credentials: Credentials,
     pub mapping_rules: Vec<MappingRule>,
+    pub passthrough_params: Option<Vec<ParamPassthrough>>,
+    // metric to report when no mapping rule matches, "{method}" is replaced
+    // by the lowercase request method
+    pub method_metric: Option<String>,
+    pub backend_paths: Option<BackendPaths>,
+    pub signature: Option<Signature>,
+    // remove ";"-delimited parameters from path segments for rule matching
+    pub strip_matrix_params: Option<bool>,
+    // app ids or user keys authorized without having their usage reported
+    pub unmetered_apps: Option<Vec<String>>,
+    // tell clients in a response header the kind of source credentials came from
+    pub auth_source_header: Option<bool>,
+    // where to look up the plan of the application
+    pub plan: Option<Vec<Source>>,
+    pub usage_merge: Option<UsageMerge>,
+    // tell clients in a response header the plan returned by 3scale
+    pub plan_header: Option<bool>,
+    // only authorize up front, and report usage once the upstream succeeded
+    pub report_on_response: Option<bool>,
+    // overrides of the top level handling of requests failing authorization
+    pub failure_policy: Option<FailurePolicy>,
+    // calls are gRPC, matched on their method names and told successful by grpc-status
+    pub grpc: Option<bool>,
+    // backend cluster, URL and timeout used for this service instead of the top level ones
+    pub upstream: Option<Upstream>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub no_match_behavior: Option<NoMatchBehavior>,
+    // usage derived from the responses of the upstream
+    pub response_rules: Option<Vec<ResponseRule>>,
+    // name of the backend of this service when not the default one
+    pub backend: Option<String>,
+    pub bypass: Option<Bypass>,
+    pub sanitize: Option<Sanitize>,
 }
 
 impl Service {
@@ -47,13 +304,15 @@ impl Service {
         self.id.as_str()
     }
 
-    // Allow dead code until we have the logic asking for the environment
-    // in the configuration retrieval subsystem.
-    #[allow(dead_code)]
     pub fn environment(&self) -> Environment {
         self.environment
     }
 
+    // Services with an unknown environment, likely a typo, never match.
+    pub fn is_in_environment(&self, environment: Environment) -> bool {
+        self.environment != Environment::Unknown && self.environment == environment
+    }
+
     pub fn token(&self) -> Option<&str> {
         self.token.as_deref()
     }
@@ -66,7 +325,213 @@ impl Service {
         self.mapping_rules.as_ref()
     }
 
+    pub fn passthrough_params(&self) -> Option<&Vec<ParamPassthrough>> {
+        self.passthrough_params.as_ref()
+    }
+
+    pub fn method_metric(&self) -> Option<&str> {
+        self.method_metric.as_deref()
+    }
+
+    pub fn backend_path(&self, kind: &Kind) -> Option<&str> {
+        self.backend_paths
+            .as_ref()
+            .and_then(|paths| paths.path(kind))
+    }
+
+    pub fn signature(&self) -> Option<&Signature> {
+        self.signature.as_ref()
+    }
+
+    pub fn strip_matrix_params(&self) -> bool {
+        self.strip_matrix_params.unwrap_or(false)
+    }
+
+    pub fn is_unmetered(&self, app_id: &str) -> bool {
+        self.unmetered_apps
+            .as_ref()
+            .map_or(false, |apps| apps.iter().any(|id| id == app_id))
+    }
+
+    pub fn auth_source_header(&self) -> bool {
+        self.auth_source_header.unwrap_or(false)
+    }
+
+    pub fn plan(&self) -> Option<&Vec<Source>> {
+        self.plan.as_ref()
+    }
+
+    pub fn usage_merge(&self) -> UsageMerge {
+        self.usage_merge.unwrap_or_default()
+    }
+
+    pub fn plan_header(&self) -> bool {
+        self.plan_header.unwrap_or(false)
+    }
+
+    pub fn report_on_response(&self) -> bool {
+        self.report_on_response.unwrap_or(false)
+    }
+
+    pub const fn failure_policy(&self) -> Option<&FailurePolicy> {
+        self.failure_policy.as_ref()
+    }
+
+    pub fn is_grpc(&self) -> bool {
+        self.grpc.unwrap_or(false)
+    }
+
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_deref()
+    }
+
+    pub const fn bypass(&self) -> Option<&Bypass> {
+        self.bypass.as_ref()
+    }
+
+    pub const fn sanitize(&self) -> Option<&Sanitize> {
+        self.sanitize.as_ref()
+    }
+
+    pub fn backend_upstream<'a>(&'a self, backend: &'a Backend) -> &'a Upstream {
+        self.upstream.as_ref().unwrap_or_else(|| backend.upstream())
+    }
+
+    pub const fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    pub const fn no_match_behavior(&self) -> Option<&NoMatchBehavior> {
+        self.no_match_behavior.as_ref()
+    }
+
+    pub fn response_rules(&self) -> Option<&[ResponseRule]> {
+        self.response_rules.as_deref()
+    }
+
+    #[allow(dead_code)]
     pub fn match_authority(&self, authority: &str) -> bool {
         self.authorities.is_match(authority)
     }
+
+    pub fn authority_specificity(&self, authority: &str) -> Option<Specificity> {
+        self.authorities.specificity(authority)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn service_in(environment: &str) -> Service {
+        serde_json::from_str(&format!(
+            r#"{{
+              "id": "2555417834780",
+              "environment": "{}",
+              "credentials": {{
+                "user_key": [{{ "header": {{ "keys": ["x-api-key"] }} }}]
+              }},
+              "mapping_rules": []
+            }}"#,
+            environment
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn bypasses_matching_paths_and_methods() {
+        let bypass: Bypass = serde_json::from_str(
+            r#"{
+              "rules": [
+                { "paths": ["/healthz", "/metrics*"], "methods": ["GET"] },
+                { "paths": ["*"], "methods": ["options"] }
+              ]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(bypass.is_match("GET", "/healthz"));
+        assert!(bypass.is_match("GET", "/metrics/prometheus"));
+        assert!(!bypass.is_match("POST", "/healthz"));
+        assert!(bypass.is_match("OPTIONS", "/users/1"));
+        assert!(!bypass.is_match("GET", "/users/1"));
+        assert_eq!(bypass.anonymous_app(), None);
+    }
+
+    #[test]
+    fn finds_client_ips() {
+        let peer: ClientIpLimit =
+            serde_json::from_str(r#"{ "requests": 10, "period": 60 }"#).unwrap();
+        assert_eq!(peer.client_ip("10.0.0.1:51234"), Some("10.0.0.1"));
+        assert_eq!(peer.client_ip("[2001:db8::1]:443"), Some("2001:db8::1"));
+        assert_eq!(peer.client_ip("2001:db8::1"), Some("2001:db8::1"));
+        assert_eq!(peer.client_ip(""), None);
+
+        let forwarded: ClientIpLimit = serde_json::from_str(
+            r#"{ "requests": 10, "period": 60, "header": "x-forwarded-for" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            forwarded.client_ip("203.0.113.7, 10.0.0.1"),
+            Some("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn sanitizes_threescale_headers() {
+        let sanitize: Sanitize =
+            serde_json::from_str(r#"{ "request_headers": true, "response_headers": true }"#)
+                .unwrap();
+
+        assert!(sanitize.strips_request_header("x-3scale-service-id"));
+        assert!(sanitize.strips_request_header("X-3scale-App-Id"));
+        assert!(!sanitize.strips_request_header("powered-by"));
+        assert!(!sanitize.strips_request_header("x-3scal"));
+        assert!(sanitize.strips_response_header("x-3scale-usages"));
+        assert!(sanitize.strips_response_header("Powered-By"));
+        assert!(!sanitize.strips_response_header("content-type"));
+
+        let sanitize: Sanitize = serde_json::from_str(r#"{ "request_headers": true }"#).unwrap();
+        assert!(!sanitize.strips_response_header("x-3scale-usages"));
+    }
+
+    #[test]
+    fn parses_no_match_behaviors() {
+        let behavior = |json| serde_json::from_str::<NoMatchBehavior>(json).unwrap();
+
+        assert_eq!(
+            behavior(r#"{ "action": "deny" }"#),
+            NoMatchBehavior::default()
+        );
+        assert_eq!(
+            behavior(r#"{ "action": "deny", "status": 404 }"#),
+            NoMatchBehavior::Deny { status: Some(404) }
+        );
+        assert_eq!(
+            behavior(r#"{ "action": "allow_without_report" }"#),
+            NoMatchBehavior::AllowWithoutReport
+        );
+        assert_eq!(
+            behavior(r#"{ "action": "report_default_metric", "metric": "Hits" }"#),
+            NoMatchBehavior::ReportDefaultMetric {
+                metric: "Hits".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn unexpected_environments_are_unknown() {
+        assert_eq!(service_in("staging").environment(), Environment::Staging);
+        assert_eq!(service_in("prod").environment(), Environment::Unknown);
+    }
+
+    #[test]
+    fn unknown_environments_never_match() {
+        let service = service_in("prod");
+
+        assert!(!service.is_in_environment(Environment::Production));
+        assert!(!service.is_in_environment(Environment::Unknown));
+        assert!(service_in("production").is_in_environment(Environment::Production));
+        assert!(!service_in("staging").is_in_environment(Environment::Production));
+    }
 }
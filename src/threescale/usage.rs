@@ -1,9 +1,13 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Usage {
     pub name: String,
     pub delta: i64,
+    // metric names to use instead for applications on the given plans
+    pub plans: Option<BTreeMap<String, String>>,
 }
 
 impl Usage {
@@ -11,6 +15,12 @@ impl Usage {
         self.name.as_str()
     }
 
+    // name of the metric to report for an application on the given plan
+    pub fn name_for(&self, plan: Option<&str>) -> &str {
+        plan.and_then(|plan| self.plans.as_ref().and_then(|plans| plans.get(plan)))
+            .map_or_else(|| self.name(), String::as_str)
+    }
+
     pub const fn delta(&self) -> i64 {
         self.delta
     }
@@ -100,12 +100,21 @@ impl Upstream {
         );
         ctx.dispatch_http_call(name, hdrs, body, trailers, timeout)
             .map_err(|e| {
+                // calls can only target clusters known to the proxy, not arbitrary endpoints
+                let hint = match e {
+                    proxy_wasm::types::Status::BadArgument
+                    | proxy_wasm::types::Status::NotFound => {
+                        " (the upstream name must match a cluster defined in the proxy)"
+                    }
+                    _ => "",
+                };
                 anyhow!(
-                    "failed to dispatch HTTP ({}) call to cluster {} with authority {}: {:?}",
+                    "failed to dispatch HTTP ({}) call to cluster {} with authority {}: {:?}{}",
                     scheme,
                     name,
                     authority,
-                    e
+                    e,
+                    hint
                 )
             })
     }
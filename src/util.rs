@@ -1,3 +1,4 @@
 pub mod glob;
+pub mod jwt;
 pub mod rand;
 pub mod serde;
@@ -129,6 +129,46 @@ fn regex_unescape_specials(specials: &[char], regex_s: &str) -> String {
         })
 }
 
+// How specific a pattern is: the longer the literal prefix the more specific,
+// and patterns without wildcards are more specific than those with them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Specificity {
+    literal_prefix: usize,
+    literal: bool,
+}
+
+impl Specificity {
+    // Computed from the regular expression built for a glob pattern, in which
+    // only wildcards are left as unescaped dots.
+    fn from_regex_pattern(regex_pattern: &str) -> Self {
+        let pattern = regex_pattern
+            .trim_start_matches(r"\A")
+            .trim_end_matches(r"\z");
+        let mut literal_prefix = 0;
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let _ = chars.next();
+                }
+                '.' => {
+                    return Self {
+                        literal_prefix,
+                        literal: false,
+                    }
+                }
+                _ => (),
+            }
+            literal_prefix += 1;
+        }
+
+        Self {
+            literal_prefix,
+            literal: true,
+        }
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(try_from = "Vec<String>", into = "Vec<String>")]
@@ -176,6 +216,16 @@ impl GlobPatternSet {
         self.0.is_match(s)
     }
 
+    // Specificity of the most specific pattern matching the input, if any.
+    pub fn specificity(&self, s: &str) -> Option<Specificity> {
+        let patterns = self.0.patterns();
+        self.0
+            .matches(s)
+            .iter()
+            .map(|idx| Specificity::from_regex_pattern(patterns[idx].as_str()))
+            .max()
+    }
+
     pub fn regex_set(&self) -> &RegexSet {
         &self.0
     }
@@ -263,6 +313,40 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn most_specific_pattern_is_preferred() -> Result<(), Error> {
+        let set = GlobPatternSet::try_from(vec![
+            "*".to_string(),
+            "api.*".to_string(),
+            "api.example.com".to_string(),
+        ])?;
+        let catch_all = GlobPatternSet::try_from("*")?;
+        let prefixed = GlobPatternSet::try_from("api.ex*")?;
+        let question = GlobPatternSet::try_from(r"api\?.example.com")?;
+
+        let exact = set.specificity("api.example.com").unwrap();
+        let wildcard = set.specificity("api.example.org").unwrap();
+        assert!(exact > wildcard);
+        assert!(wildcard > catch_all.specificity("api.example.org").unwrap());
+        assert!(prefixed.specificity("api.example.org").unwrap() > wildcard);
+        // a literal pattern beats a wildcard one with the same literal prefix
+        assert!(
+            GlobPatternSet::try_from("api.example.com*")?
+                .specificity("api.example.com")
+                .unwrap()
+                < exact
+        );
+        // escaped wildcards are literal characters
+        assert!(question.specificity("api?.example.com").unwrap() > exact);
+        assert_eq!(
+            set.specificity("other.example.com"),
+            catch_all.specificity("x")
+        );
+        assert_eq!(prefixed.specificity("other.example.com"), None);
+
+        Ok(())
+    }
+
     mod unescape_logic {
         use super::*;
 
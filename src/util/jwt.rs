@@ -0,0 +1,171 @@
+// Decoding of the claims of JSON Web Tokens (RFC 7519).
+//
+// Signatures are not verified here: tokens are expected to have been verified
+// by an earlier filter, such as Envoy's jwt_authn, and only their payload is
+// decoded to check the registered claims and look up the application.
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum Error {
+    #[error("malformed token")]
+    Malformed,
+    #[error("failed to decode token payload")]
+    Payload,
+    #[error("issuer {0:?} not accepted")]
+    Issuer(Option<String>),
+    #[error("audience not accepted")]
+    Audience,
+    #[error("token expired")]
+    Expired,
+    #[error("token has no expiration time")]
+    NoExpiration,
+    #[error("claim {0} is not a string")]
+    NotAString(String),
+}
+
+// Tokens in the authorization header come after the scheme.
+pub fn strip_bearer(value: &str) -> &str {
+    const BEARER: &str = "bearer ";
+
+    match value.get(..BEARER.len()) {
+        Some(scheme) if scheme.eq_ignore_ascii_case(BEARER) => value[BEARER.len()..].trim(),
+        _ => value.trim(),
+    }
+}
+
+pub fn payload(token: &str) -> Result<Value, Error> {
+    let mut parts = token.split('.');
+    let payload = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(_), Some(payload), Some(_), None) => payload,
+        _ => return Err(Error::Malformed),
+    };
+    let payload =
+        base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| Error::Payload)?;
+    serde_json::from_slice(payload.as_slice()).map_err(|_| Error::Payload)
+}
+
+// Checks the issuer and audience claims if required, and the expiration
+// time if given the current time in seconds, rejecting tokens without one.
+pub fn validate(
+    payload: &Value,
+    issuer: Option<&str>,
+    audiences: Option<&[String]>,
+    now: Option<u64>,
+) -> Result<(), Error> {
+    if let Some(issuer) = issuer {
+        let iss = payload.get("iss").and_then(Value::as_str);
+        if iss != Some(issuer) {
+            return Err(Error::Issuer(iss.map(str::to_string)));
+        }
+    }
+
+    if let Some(audiences) = audiences {
+        // the audience is either a single string or a list of them
+        let accepted = match payload.get("aud") {
+            Some(Value::String(aud)) => audiences.contains(aud),
+            Some(Value::Array(auds)) => auds
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|aud| audiences.iter().any(|a| a == aud)),
+            _ => false,
+        };
+        if !accepted {
+            return Err(Error::Audience);
+        }
+    }
+
+    if let Some(now) = now {
+        match payload.get("exp").and_then(Value::as_f64) {
+            Some(exp) if exp > now as f64 => (),
+            Some(_) => return Err(Error::Expired),
+            None => return Err(Error::NoExpiration),
+        }
+    }
+
+    Ok(())
+}
+
+// Looks up a claim either by name or, when starting with a slash, by a JSON
+// pointer (RFC 6901) for nested claims. Lists resolve to their first element,
+// as is the case of multiple audiences.
+pub fn claim<'a>(payload: &'a Value, claim: &str) -> Result<Option<&'a str>, Error> {
+    let value = if claim.starts_with('/') {
+        payload.pointer(claim)
+    } else {
+        payload.get(claim)
+    };
+    let value = match value {
+        Some(Value::Array(values)) => values.first(),
+        value => value,
+    };
+
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.as_str())),
+        Some(_) => Err(Error::NotAString(claim.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn token(payload: &str) -> String {
+        format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.c2lnbmF0dXJl",
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    #[test]
+    fn decodes_claims() {
+        let token = token(
+            r#"{ "azp": "my-app", "aud": ["account", "other"], "realm": { "client": "nested" }, "exp": 10 }"#,
+        );
+        let claims = payload(strip_bearer(&format!("Bearer {}", token))).unwrap();
+
+        assert_eq!(claim(&claims, "azp"), Ok(Some("my-app")));
+        assert_eq!(claim(&claims, "aud"), Ok(Some("account")));
+        assert_eq!(claim(&claims, "/realm/client"), Ok(Some("nested")));
+        assert_eq!(claim(&claims, "client_id"), Ok(None));
+        assert_eq!(
+            claim(&claims, "exp"),
+            Err(Error::NotAString("exp".to_string()))
+        );
+        assert_eq!(payload("not-a-token"), Err(Error::Malformed));
+    }
+
+    #[test]
+    fn validates_registered_claims() {
+        let claims = payload(&token(
+            r#"{ "iss": "https://sso", "aud": "account", "exp": 10 }"#,
+        ))
+        .unwrap();
+        let audiences = ["account".to_string()];
+
+        assert_eq!(
+            validate(&claims, Some("https://sso"), Some(&audiences), Some(9)),
+            Ok(())
+        );
+        assert_eq!(
+            validate(&claims, Some("https://other"), None, None),
+            Err(Error::Issuer(Some("https://sso".to_string())))
+        );
+        assert_eq!(
+            validate(&claims, None, Some(&["other".to_string()]), None),
+            Err(Error::Audience)
+        );
+        assert_eq!(validate(&claims, None, None, Some(10)), Err(Error::Expired));
+    }
+
+    #[test]
+    fn rejects_tokens_without_expiration_when_checking_it() {
+        let claims = payload(&token(r#"{ "azp": "my-app" }"#)).unwrap();
+
+        assert_eq!(validate(&claims, None, None, None), Ok(()));
+        assert_eq!(
+            validate(&claims, None, None, Some(10)),
+            Err(Error::NoExpiration)
+        );
+    }
+}